@@ -0,0 +1,265 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    body::Incoming,
+    header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+#[cfg(not(feature = "simd-json"))]
+use serde_json::to_string;
+#[cfg(feature = "simd-json")]
+use simd_json::to_string;
+use tokio::net::TcpListener;
+use tracing::{error, info, trace};
+use twilight_gateway::{CloseFrame, ShardState as ConnectionState};
+
+use std::{convert::Infallible, future::ready, net::SocketAddr, time::UNIX_EPOCH};
+
+use crate::{auth::constant_time_eq, config, state::State};
+
+#[derive(Serialize)]
+struct ShardInfo {
+    id: u32,
+    status: &'static str,
+    latency_ms: Option<u64>,
+    ready: bool,
+    guilds: usize,
+    unavailable_guilds: usize,
+}
+
+#[derive(Serialize)]
+struct SessionInfo<'a> {
+    id: &'a str,
+    shard_id: u32,
+    group: Option<&'a str>,
+    credential: Option<&'a str>,
+    connected: bool,
+    address: Option<String>,
+    compression: Option<&'static str>,
+    compress: Option<bool>,
+    connected_at: Option<u64>,
+    lagged_events: u64,
+    commands: u64,
+    queue_messages: Option<usize>,
+    queue_bytes: Option<usize>,
+}
+
+const fn connection_name(connection: ConnectionState) -> &'static str {
+    match connection {
+        ConnectionState::Active => "active",
+        ConnectionState::Disconnected { .. } => "disconnected",
+        ConnectionState::Identifying => "identifying",
+        ConnectionState::Resuming => "resuming",
+        ConnectionState::FatallyClosed => "fatally_closed",
+    }
+}
+
+pub fn empty(status: StatusCode) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(Full::default())
+        .unwrap()
+}
+
+pub fn json(value: &impl Serialize) -> Response<Full<Bytes>> {
+    let Ok(body) = to_string(value) else {
+        return empty(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .body(Full::from(body))
+        .unwrap()
+}
+
+/// Check the bearer token of a request against the configured admin token.
+fn is_authorized(request: &Request<Incoming>) -> bool {
+    let Some(admin) = &config::current().admin else {
+        return false;
+    };
+
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), admin.token.as_bytes()))
+}
+
+fn list_shards(state: &State) -> Response<Full<Bytes>> {
+    let shards: Vec<_> = state
+        .get()
+        .shards
+        .iter()
+        .map(|shard| {
+            let status = *shard.status.lock().unwrap();
+            let cache_stats = shard.guilds.stats();
+
+            ShardInfo {
+                id: shard.id,
+                status: connection_name(status.connection),
+                latency_ms: status.latency.map(|latency| latency.as_millis() as u64),
+                ready: shard.ready.is_ready(),
+                guilds: cache_stats.guilds(),
+                unavailable_guilds: cache_stats.unavailable_guilds(),
+            }
+        })
+        .collect();
+
+    json(&shards)
+}
+
+fn list_sessions(state: &State) -> Response<Full<Bytes>> {
+    let sessions: Vec<_> = state
+        .get()
+        .sessions
+        .read()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect();
+
+    let sessions: Vec<_> = sessions
+        .iter()
+        .map(|session| {
+            let client = session.client();
+            let queue_depth = session.queue_depth();
+
+            SessionInfo {
+                id: &session.id,
+                shard_id: session.shard_id,
+                group: session.group.as_deref(),
+                credential: session.credential.as_deref(),
+                connected: client.is_some(),
+                address: client.map(|client| client.addr.to_string()),
+                compression: client.and_then(|client| client.compression.name()),
+                compress: session.compress,
+                connected_at: client.and_then(|client| {
+                    client
+                        .connected_at
+                        .duration_since(UNIX_EPOCH)
+                        .ok()
+                        .map(|since| since.as_secs())
+                }),
+                lagged_events: session.lagged_events(),
+                commands: session.commands(),
+                queue_messages: queue_depth.map(|(messages, _)| messages),
+                queue_bytes: queue_depth.map(|(_, bytes)| bytes),
+            }
+        })
+        .collect();
+
+    json(&sessions)
+}
+
+/// Close the connection of a shard, which makes it reconnect right away.
+///
+/// A resumable close code keeps the Discord session, otherwise the shard
+/// identifies again.
+fn reconnect_shard(state: &State, shard_id: &str, resume: bool) -> Response<Full<Bytes>> {
+    let Some(shard) = shard_id
+        .parse()
+        .ok()
+        .and_then(|shard_id| state.get().shard(shard_id))
+    else {
+        return empty(StatusCode::NOT_FOUND);
+    };
+
+    let close_frame = if resume {
+        CloseFrame::RESUME
+    } else {
+        CloseFrame::NORMAL
+    };
+
+    info!(
+        "[Shard {}] Reconnecting on admin request, resume: {resume}",
+        shard.id
+    );
+
+    if shard.sender.close(close_frame).is_err() {
+        return empty(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    empty(StatusCode::ACCEPTED)
+}
+
+fn kick_session(state: &State, session_id: &str) -> Response<Full<Bytes>> {
+    if state.get().kick_session(session_id) {
+        info!("Kicked session {session_id} on admin request");
+        empty(StatusCode::NO_CONTENT)
+    } else {
+        empty(StatusCode::NOT_FOUND)
+    }
+}
+
+fn handler(request: &Request<Incoming>, state: &State) -> Response<Full<Bytes>> {
+    if !is_authorized(request) {
+        let mut response = empty(StatusCode::UNAUTHORIZED);
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+
+        return response;
+    }
+
+    let segments: Vec<_> = request.uri().path().trim_matches('/').split('/').collect();
+
+    match (request.method(), segments.as_slice()) {
+        (&Method::GET, ["shards"]) => list_shards(state),
+        (&Method::POST, ["shards", shard_id, "reconnect"]) => {
+            reconnect_shard(state, shard_id, true)
+        }
+        (&Method::POST, ["shards", shard_id, "reidentify"]) => {
+            reconnect_shard(state, shard_id, false)
+        }
+        (&Method::GET, ["sessions"]) => list_sessions(state),
+        (&Method::DELETE, ["sessions", session_id]) => kick_session(state, session_id),
+        _ => empty(StatusCode::NOT_FOUND),
+    }
+}
+
+/// Serve the admin API, which is kept apart from the gateway listener.
+pub async fn run(addr: SocketAddr, state: State) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind admin TCP listener: {e}");
+            return;
+        }
+    };
+
+    info!("Admin API listening on {addr}");
+
+    loop {
+        let (conn, addr) = match listener.accept().await {
+            Ok((stream, addr)) => (stream, addr),
+            Err(e) => {
+                error!("Failed to accept admin connection: {e}");
+                continue;
+            }
+        };
+
+        trace!("[{addr:?}] New admin connection");
+
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(
+                    TokioIo::new(conn),
+                    service_fn(move |incoming: Request<Incoming>| {
+                        ready(Ok::<_, Infallible>(handler(&incoming, &state)))
+                    }),
+                )
+                .await
+            {
+                error!("Error handling admin connection: {e}");
+            }
+        });
+    }
+}
@@ -0,0 +1,149 @@
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    body::Incoming,
+    header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE},
+    Request, Response, StatusCode,
+};
+use serde::Serialize;
+use twilight_model::{
+    guild::Permissions,
+    id::{marker::GuildMarker, Id},
+};
+
+use std::sync::Arc;
+
+use crate::{
+    admin::{empty, json},
+    auth,
+    cache::Guilds,
+    config,
+    state::{Shard, State},
+    upgrade::query_param,
+};
+
+#[derive(Serialize)]
+struct MemberPermissions {
+    permissions: Permissions,
+}
+
+fn found(value: Option<impl Serialize>) -> Response<Full<Bytes>> {
+    value.map_or_else(|| empty(StatusCode::NOT_FOUND), |value| json(&value))
+}
+
+fn parse_id<T>(id: &str) -> Option<Id<T>> {
+    id.parse().ok()
+}
+
+/// Get the shard that a guild is on, if it is managed by the proxy.
+fn guild_shard(state: &State, guild_id: Id<GuildMarker>) -> Option<Arc<Shard>> {
+    let inner = state.get();
+    let shard_id = (guild_id.get() >> 22) % u64::from(inner.shard_count);
+
+    inner.shard(u32::try_from(shard_id).ok()?)
+}
+
+/// Compute the permissions of a member, in the channel from the `channel`
+/// query parameter if it is given.
+fn member_permissions(
+    request: &Request<Incoming>,
+    guilds: &Guilds,
+    guild_id: Id<GuildMarker>,
+    user_id: &str,
+) -> Response<Full<Bytes>> {
+    let channel = request
+        .uri()
+        .query()
+        .and_then(|query| query_param(query, "channel"));
+
+    let channel_id = match channel.as_deref().map(parse_id) {
+        Some(Some(channel_id)) => Some(channel_id),
+        Some(None) => return empty(StatusCode::BAD_REQUEST),
+        None => None,
+    };
+
+    found(
+        parse_id(user_id)
+            .and_then(|user_id| guilds.permissions(guild_id, user_id, channel_id))
+            .map(|permissions| MemberPermissions { permissions }),
+    )
+}
+
+/// Serve the cached resources of a guild.
+///
+/// Requests are authorized with the same tokens that clients identify with,
+/// and credentials can only read the guilds on their shards. Unlike clients,
+/// requests are never let in with an arbitrary token, even if tokens are not
+/// validated.
+pub fn handler(request: &Request<Incoming>, state: &State) -> Response<Full<Bytes>> {
+    let validates_tokens = auth::validates_tokens(&config::current());
+
+    let Some(access) = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(auth::authenticate)
+        .filter(|_| validates_tokens)
+    else {
+        let mut response = empty(StatusCode::UNAUTHORIZED);
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bot"));
+
+        return response;
+    };
+
+    let segments: Vec<_> = request.uri().path().trim_matches('/').split('/').collect();
+
+    let ["guilds", guild_id, resource @ ..] = segments.as_slice() else {
+        return empty(StatusCode::NOT_FOUND);
+    };
+
+    let Some((guild_id, shard)) =
+        parse_id(guild_id).and_then(|guild_id| Some((guild_id, guild_shard(state, guild_id)?)))
+    else {
+        return empty(StatusCode::NOT_FOUND);
+    };
+
+    if !access.allows_shard(shard.id) {
+        return empty(StatusCode::FORBIDDEN);
+    }
+
+    let guilds = &shard.guilds;
+
+    if !guilds.has_guild(guild_id) {
+        return empty(StatusCode::NOT_FOUND);
+    }
+
+    match resource {
+        [] => found(guilds.get_guild(guild_id)),
+        ["channels"] => json(&guilds.channels_in_guild(guild_id)),
+        ["channels", channel_id] => {
+            found(parse_id(channel_id).and_then(|channel_id| guilds.channel(guild_id, channel_id)))
+        }
+        ["roles"] => json(&guilds.roles_in_guild(guild_id)),
+        ["roles", role_id] => {
+            found(parse_id(role_id).and_then(|role_id| guilds.role(guild_id, role_id)))
+        }
+        ["members"] => json(&guilds.members_in_guild(guild_id)),
+        ["members", user_id] => {
+            found(parse_id(user_id).and_then(|user_id| guilds.member(guild_id, user_id)))
+        }
+        ["members", user_id, "permissions"] => {
+            member_permissions(request, guilds, guild_id, user_id)
+        }
+        ["emojis"] => json(&guilds.emojis_in_guild(guild_id)),
+        ["emojis", emoji_id] => {
+            found(parse_id(emoji_id).and_then(|emoji_id| guilds.emoji(guild_id, emoji_id)))
+        }
+        ["voice-states"] => json(&guilds.voice_states_in_guild(guild_id)),
+        ["voice-states", user_id] => {
+            found(parse_id(user_id).and_then(|user_id| guilds.voice_state(guild_id, user_id)))
+        }
+        ["presences"] => json(&guilds.presences_in_guild(guild_id)),
+        ["presences", user_id] => {
+            found(parse_id(user_id).and_then(|user_id| guilds.presence(guild_id, user_id)))
+        }
+        _ => empty(StatusCode::NOT_FOUND),
+    }
+}
@@ -0,0 +1,89 @@
+use std::hint::black_box;
+
+use crate::config::{self, Config, Credential, CONFIG};
+
+/// Access that the token of a client grants.
+pub enum Access {
+    /// The bot token, or any token if tokens are not validated and no
+    /// credentials are configured.
+    Full,
+    /// A credential from the config that may be limited to some shards and
+    /// opcodes.
+    Credential(Credential),
+}
+
+impl Access {
+    /// Name of the credential, if any.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Self::Full => None,
+            Self::Credential(credential) => Some(&credential.name),
+        }
+    }
+
+    pub fn allows_shard(&self, shard_id: u32) -> bool {
+        match self {
+            Self::Full => true,
+            Self::Credential(credential) => credential.allows_shard(shard_id),
+        }
+    }
+
+    pub fn allows_opcode(&self, op: u8) -> bool {
+        match self {
+            Self::Full => true,
+            Self::Credential(credential) => credential.allows_opcode(op),
+        }
+    }
+}
+
+/// Compare two byte strings in a time that only depends on their lengths.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let difference = a
+        .iter()
+        .zip(b)
+        .fold(0, |difference, (x, y)| black_box(difference | (x ^ y)));
+
+    difference == 0
+}
+
+/// Whether unknown tokens are rejected.
+///
+/// Configuring credentials would be pointless if any other token had full
+/// access, so tokens are always validated then.
+pub const fn validates_tokens(config: &Config) -> bool {
+    config.validate_token || !config.credentials.is_empty()
+}
+
+/// Find the access that a token from IDENTIFY or RESUME grants.
+///
+/// Returns [`None`] if the token is not accepted.
+pub fn authenticate(token: &str) -> Option<Access> {
+    let config = config::current();
+
+    // Discord tokens may be prefixed by 'Bot '
+    let token = token.split_whitespace().last().unwrap_or_default();
+
+    // Every credential is compared so that the time taken does not reveal
+    // which one matched
+    let mut matched = None;
+
+    for credential in &config.credentials {
+        if constant_time_eq(token.as_bytes(), credential.token.as_bytes()) {
+            matched = Some(credential);
+        }
+    }
+
+    if let Some(credential) = matched {
+        Some(Access::Credential(credential.clone()))
+    } else if !validates_tokens(&config)
+        || constant_time_eq(token.as_bytes(), CONFIG.token.as_bytes())
+    {
+        Some(Access::Full)
+    } else {
+        None
+    }
+}
@@ -5,17 +5,23 @@ use serde::Serialize;
 use serde_json::{to_string, Value as OwnedValue};
 #[cfg(feature = "simd-json")]
 use simd_json::{to_string, OwnedValue};
-use twilight_cache_inmemory::{DefaultCacheModels, InMemoryCache, InMemoryCacheStats, UpdateCache};
+use twilight_cache_inmemory::{
+    model::CachedGuild, DefaultCacheModels, InMemoryCache, InMemoryCacheStats, UpdateCache,
+};
+use twilight_gateway::Intents;
 use twilight_model::{
     channel::{message::Sticker, Channel, StageInstance},
     gateway::{
-        payload::incoming::GuildDelete,
+        payload::{
+            incoming::{GuildDelete, MemberChunk},
+            outgoing::request_guild_members::{RequestGuildMemberId, RequestGuildMembersInfo},
+        },
         presence::{Presence, UserOrId},
         OpCode,
     },
-    guild::{scheduled_event::GuildScheduledEvent, Emoji, Guild, Member, Role},
+    guild::{scheduled_event::GuildScheduledEvent, Emoji, Guild, Member, Permissions, Role},
     id::{
-        marker::{GuildMarker, UserMarker},
+        marker::{ChannelMarker, EmojiMarker, GuildMarker, RoleMarker, UserMarker},
         Id,
     },
     voice::VoiceState,
@@ -23,7 +29,13 @@ use twilight_model::{
 
 use std::sync::Arc;
 
-use crate::model::JsonObject;
+use crate::{config::CONFIG, model::JsonObject};
+
+/// Maximum number of members in a `GUILD_MEMBERS_CHUNK`.
+const MEMBER_CHUNK_SIZE: usize = 1000;
+
+/// Most members that Discord sends for a request with a query or limit.
+const MEMBER_QUERY_LIMIT: usize = 100;
 
 #[derive(Serialize)]
 pub struct Payload<T> {
@@ -44,8 +56,16 @@ impl Guilds {
         self.0.update(value);
     }
 
+    pub fn clear(&self) {
+        self.0.clear();
+    }
+
+    pub fn has_guild(&self, guild_id: Id<GuildMarker>) -> bool {
+        self.0.guild(guild_id).is_some()
+    }
+
     #[allow(clippy::missing_const_for_fn)]
-    pub fn stats(&self) -> InMemoryCacheStats {
+    pub fn stats(&self) -> InMemoryCacheStats<'_> {
         self.0.stats()
     }
 
@@ -98,7 +118,17 @@ impl Guilds {
         }
     }
 
-    fn channels_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Channel> {
+    pub fn channel(
+        &self,
+        guild_id: Id<GuildMarker>,
+        channel_id: Id<ChannelMarker>,
+    ) -> Option<Channel> {
+        let channel = self.0.channel(channel_id)?;
+
+        (channel.guild_id == Some(guild_id)).then(|| channel.value().clone())
+    }
+
+    pub fn channels_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Channel> {
         let channel_ids: Vec<_> = self
             .0
             .guild_channels(guild_id)
@@ -119,58 +149,66 @@ impl Guilds {
             .collect()
     }
 
-    fn presences_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Presence> {
+    pub fn presence(&self, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) -> Option<Presence> {
+        let presence = self.0.presence(guild_id, user_id)?;
+
+        Some(Presence {
+            activities: presence.activities().to_vec(),
+            client_status: presence.client_status().clone(),
+            guild_id: presence.guild_id(),
+            status: presence.status(),
+            user: UserOrId::UserId {
+                id: presence.user_id(),
+            },
+        })
+    }
+
+    pub fn presences_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Presence> {
         self.0
             .guild_presences(guild_id)
             .map(|reference| {
                 reference
                     .iter()
-                    .filter_map(|user_id| {
-                        let presence = self.0.presence(guild_id, *user_id)?;
-
-                        Some(Presence {
-                            activities: presence.activities().to_vec(),
-                            client_status: presence.client_status().clone(),
-                            guild_id: presence.guild_id(),
-                            status: presence.status(),
-                            user: UserOrId::UserId {
-                                id: presence.user_id(),
-                            },
-                        })
-                    })
+                    .filter_map(|user_id| self.presence(guild_id, *user_id))
                     .collect()
             })
             .unwrap_or_default()
     }
 
-    fn emojis_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Emoji> {
+    pub fn emoji(&self, guild_id: Id<GuildMarker>, emoji_id: Id<EmojiMarker>) -> Option<Emoji> {
+        let emoji = self.0.emoji(emoji_id)?;
+
+        if emoji.guild_id() != guild_id {
+            return None;
+        }
+
+        Some(Emoji {
+            animated: emoji.animated(),
+            available: emoji.available(),
+            id: emoji.id(),
+            managed: emoji.managed(),
+            name: emoji.name().to_string(),
+            require_colons: emoji.require_colons(),
+            roles: emoji.roles().to_vec(),
+            user: emoji
+                .user_id()
+                .and_then(|id| self.0.user(id).map(|user| user.value().clone())),
+        })
+    }
+
+    pub fn emojis_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Emoji> {
         self.0
             .guild_emojis(guild_id)
             .map(|reference| {
                 reference
                     .iter()
-                    .filter_map(|emoji_id| {
-                        let emoji = self.0.emoji(*emoji_id)?;
-
-                        Some(Emoji {
-                            animated: emoji.animated(),
-                            available: emoji.available(),
-                            id: emoji.id(),
-                            managed: emoji.managed(),
-                            name: emoji.name().to_string(),
-                            require_colons: emoji.require_colons(),
-                            roles: emoji.roles().to_vec(),
-                            user: emoji
-                                .user_id()
-                                .and_then(|id| self.0.user(id).map(|user| user.value().clone())),
-                        })
-                    })
+                    .filter_map(|emoji_id| self.emoji(guild_id, *emoji_id))
                     .collect()
             })
             .unwrap_or_default()
     }
 
-    fn member(&self, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) -> Option<Member> {
+    pub fn member(&self, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) -> Option<Member> {
         let member = self.0.member(guild_id, user_id)?;
 
         Some(Member {
@@ -188,7 +226,7 @@ impl Guilds {
         })
     }
 
-    fn members_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Member> {
+    pub fn members_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Member> {
         self.0
             .guild_members(guild_id)
             .map(|reference| {
@@ -200,7 +238,166 @@ impl Guilds {
             .unwrap_or_default()
     }
 
-    fn roles_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Role> {
+    /// Whether the username, global name or nickname of a member starts with
+    /// a lowercase query.
+    fn member_matches(
+        &self,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        query: &str,
+    ) -> bool {
+        let starts_with = |name: &str| name.to_lowercase().starts_with(query);
+
+        self.0.user(user_id).is_some_and(|user| {
+            starts_with(&user.name) || user.global_name.as_deref().is_some_and(starts_with)
+        }) || self
+            .0
+            .member(guild_id, user_id)
+            .is_some_and(|member| member.nick().is_some_and(starts_with))
+    }
+
+    /// Get the `GUILD_MEMBERS_CHUNK` payloads that Discord would send in
+    /// response to a Request Guild Members command.
+    ///
+    /// Requests are answered as Discord would for a session with `intents`:
+    /// presences are only included with the `GUILD_PRESENCES` intent.
+    ///
+    /// Returns [`None`] if the cache can not answer the request because not
+    /// all members of the guild, or their presences if requested, are cached,
+    /// or because the whole member list is requested without the
+    /// `GUILD_MEMBERS` intent.
+    pub fn get_member_chunks(
+        &self,
+        request: RequestGuildMembersInfo,
+        intents: Intents,
+    ) -> Option<Vec<String>> {
+        let guild_id = request.guild_id;
+        let presences =
+            request.presences.unwrap_or_default() && intents.contains(Intents::GUILD_PRESENCES);
+
+        let lists_members =
+            request.user_ids.is_none() && request.query.as_deref().is_none_or(str::is_empty);
+
+        if lists_members && !intents.contains(Intents::GUILD_MEMBERS) {
+            return None;
+        }
+
+        if !CONFIG.cache.members || !CONFIG.cache.users || (presences && !CONFIG.cache.presences) {
+            return None;
+        }
+
+        // The member count is kept up to date, so it tells whether all
+        // members are cached
+        let member_count = self.0.guild(guild_id)?.member_count()?;
+
+        let member_ids: Vec<_> = {
+            let reference = self.0.guild_members(guild_id)?;
+
+            if reference.len() as u64 != member_count {
+                return None;
+            }
+
+            reference.iter().copied().collect()
+        };
+
+        let mut not_found = Vec::new();
+
+        let members: Vec<_> = if let Some(user_ids) = request.user_ids {
+            let user_ids = match user_ids {
+                RequestGuildMemberId::One(user_id) => vec![user_id],
+                RequestGuildMemberId::Multiple(user_ids) => user_ids,
+            };
+
+            user_ids
+                .into_iter()
+                .filter_map(|user_id| {
+                    let member = self.member(guild_id, user_id);
+
+                    if member.is_none() {
+                        not_found.push(user_id);
+                    }
+
+                    member
+                })
+                .collect()
+        } else {
+            let query = request.query.unwrap_or_default().to_lowercase();
+
+            // A limit of 0 with an empty query requests all members
+            let limit = match request.limit.filter(|limit| *limit > 0) {
+                Some(limit) => (limit as usize).min(MEMBER_QUERY_LIMIT),
+                None if query.is_empty() => usize::MAX,
+                None => MEMBER_QUERY_LIMIT,
+            };
+
+            member_ids
+                .into_iter()
+                .filter(|user_id| {
+                    query.is_empty() || self.member_matches(guild_id, *user_id, &query)
+                })
+                .filter_map(|user_id| self.member(guild_id, user_id))
+                .take(limit)
+                .collect()
+        };
+
+        // Discord sends at least one chunk, even if no members matched
+        let mut chunks = Vec::new();
+        let mut members = members.into_iter();
+
+        loop {
+            let chunk: Vec<_> = members.by_ref().take(MEMBER_CHUNK_SIZE).collect();
+
+            if chunk.is_empty() && !chunks.is_empty() {
+                break;
+            }
+
+            chunks.push(chunk);
+        }
+
+        let chunk_count = chunks.len() as u32;
+
+        let payloads = chunks
+            .into_iter()
+            .enumerate()
+            .map(|(chunk_index, members)| {
+                let presences = if presences {
+                    members
+                        .iter()
+                        .filter_map(|member| self.presence(guild_id, member.user.id))
+                        .collect()
+                } else {
+                    Vec::new()
+                };
+
+                // The sequence number is set for each client
+                to_string(&Payload {
+                    d: MemberChunk {
+                        chunk_count,
+                        chunk_index: chunk_index as u32,
+                        guild_id,
+                        members,
+                        nonce: request.nonce.clone(),
+                        not_found: std::mem::take(&mut not_found),
+                        presences,
+                    },
+                    op: OpCode::Dispatch,
+                    t: "GUILD_MEMBERS_CHUNK",
+                    s: 0,
+                })
+                .unwrap()
+            })
+            .collect();
+
+        Some(payloads)
+    }
+
+    pub fn role(&self, guild_id: Id<GuildMarker>, role_id: Id<RoleMarker>) -> Option<Role> {
+        let role = self.0.role(role_id)?;
+
+        (role.guild_id() == guild_id).then(|| role.resource().clone())
+    }
+
+    pub fn roles_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Role> {
         self.0
             .guild_roles(guild_id)
             .map(|reference| {
@@ -212,6 +409,30 @@ impl Guilds {
             .unwrap_or_default()
     }
 
+    /// Compute the permissions of a member in a guild, or in a channel of the
+    /// guild if given, like Discord does.
+    ///
+    /// Returns [`None`] if the member, its roles or the channel are not cached.
+    pub fn permissions(
+        &self,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        channel_id: Option<Id<ChannelMarker>>,
+    ) -> Option<Permissions> {
+        let calculator = self.0.permissions();
+
+        let Some(channel_id) = channel_id else {
+            return calculator.root(user_id, guild_id).ok();
+        };
+
+        // Channels of other guilds are not found
+        if self.0.channel(channel_id)?.guild_id != Some(guild_id) {
+            return None;
+        }
+
+        calculator.in_channel(user_id, channel_id).ok()
+    }
+
     fn scheduled_events_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<GuildScheduledEvent> {
         self.0
             .scheduled_events(guild_id)
@@ -276,31 +497,37 @@ impl Guilds {
             .unwrap_or_default()
     }
 
-    fn voice_states_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<VoiceState> {
+    pub fn voice_state(
+        &self,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> Option<VoiceState> {
+        let voice_state = self.0.voice_state(user_id, guild_id)?;
+
+        Some(VoiceState {
+            channel_id: Some(voice_state.channel_id()),
+            deaf: voice_state.deaf(),
+            guild_id: Some(voice_state.guild_id()),
+            member: self.member(guild_id, user_id),
+            mute: voice_state.mute(),
+            self_deaf: voice_state.self_deaf(),
+            self_mute: voice_state.self_mute(),
+            self_stream: voice_state.self_stream(),
+            self_video: voice_state.self_video(),
+            session_id: voice_state.session_id().to_string(),
+            suppress: voice_state.suppress(),
+            user_id: voice_state.user_id(),
+            request_to_speak_timestamp: voice_state.request_to_speak_timestamp(),
+        })
+    }
+
+    pub fn voice_states_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<VoiceState> {
         self.0
             .guild_voice_states(guild_id)
             .map(|reference| {
                 reference
                     .iter()
-                    .filter_map(|user_id| {
-                        let voice_state = self.0.voice_state(*user_id, guild_id)?;
-
-                        Some(VoiceState {
-                            channel_id: Some(voice_state.channel_id()),
-                            deaf: voice_state.deaf(),
-                            guild_id: Some(voice_state.guild_id()),
-                            member: self.member(guild_id, *user_id),
-                            mute: voice_state.mute(),
-                            self_deaf: voice_state.self_deaf(),
-                            self_mute: voice_state.self_mute(),
-                            self_stream: voice_state.self_stream(),
-                            self_video: voice_state.self_video(),
-                            session_id: voice_state.session_id().to_string(),
-                            suppress: voice_state.suppress(),
-                            user_id: voice_state.user_id(),
-                            request_to_speak_timestamp: voice_state.request_to_speak_timestamp(),
-                        })
-                    })
+                    .filter_map(|user_id| self.voice_state(guild_id, *user_id))
                     .collect()
             })
             .unwrap_or_default()
@@ -327,11 +554,127 @@ impl Guilds {
             .collect()
     }
 
+    /// Build a guild with only the data that Discord would send in
+    /// `GUILD_CREATE` for the given intents.
+    fn guild(
+        &self,
+        guild: &CachedGuild,
+        intents: Intents,
+        current_user_id: Option<Id<UserMarker>>,
+    ) -> Guild {
+        let guild_id = guild.id();
+
+        let guild_channels = self.channels_in_guild(guild_id);
+        let presences = if intents.contains(Intents::GUILD_PRESENCES) {
+            self.presences_in_guild(guild_id)
+        } else {
+            Vec::new()
+        };
+        let emojis = self.emojis_in_guild(guild_id);
+        let members = if intents.contains(Intents::GUILD_MEMBERS) {
+            self.members_in_guild(guild_id)
+        } else {
+            // Discord always includes the bot's own member
+            current_user_id
+                .and_then(|user_id| self.member(guild_id, user_id))
+                .into_iter()
+                .collect()
+        };
+        let roles = self.roles_in_guild(guild_id);
+        let scheduled_events = self.scheduled_events_in_guild(guild_id);
+        let stage_instances = self.stage_instances_in_guild(guild_id);
+        let stickers = self.stickers_in_guild(guild_id);
+        let voice_states = if intents.contains(Intents::GUILD_VOICE_STATES) {
+            self.voice_states_in_guild(guild_id)
+        } else {
+            Vec::new()
+        };
+        let threads = self.threads_in_guild(guild_id);
+
+        Guild {
+            afk_channel_id: guild.afk_channel_id(),
+            afk_timeout: guild.afk_timeout(),
+            application_id: guild.application_id(),
+            approximate_member_count: None, // Only present in with_counts HTTP endpoint
+            banner: guild.banner().map(ToOwned::to_owned),
+            approximate_presence_count: None, // Only present in with_counts HTTP endpoint
+            channels: guild_channels,
+            default_message_notifications: guild.default_message_notifications(),
+            description: guild.description().map(ToString::to_string),
+            discovery_splash: guild.discovery_splash().map(ToOwned::to_owned),
+            emojis,
+            explicit_content_filter: guild.explicit_content_filter(),
+            features: guild.features().cloned().collect(),
+            guild_scheduled_events: scheduled_events,
+            icon: guild.icon().map(ToOwned::to_owned),
+            id: guild_id,
+            joined_at: guild.joined_at(),
+            large: guild.large(),
+            max_members: guild.max_members(),
+            max_presences: guild.max_presences(),
+            max_stage_video_channel_users: guild.max_stage_video_channel_users(),
+            max_video_channel_users: guild.max_video_channel_users(),
+            member_count: guild.member_count(),
+            members,
+            mfa_level: guild.mfa_level(),
+            name: guild.name().to_string(),
+            nsfw_level: guild.nsfw_level(),
+            owner_id: guild.owner_id(),
+            owner: guild.owner(),
+            permissions: guild.permissions(),
+            public_updates_channel_id: guild.public_updates_channel_id(),
+            preferred_locale: guild.preferred_locale().to_string(),
+            premium_progress_bar_enabled: guild.premium_progress_bar_enabled(),
+            premium_subscription_count: guild.premium_subscription_count(),
+            premium_tier: guild.premium_tier(),
+            presences,
+            roles,
+            rules_channel_id: guild.rules_channel_id(),
+            safety_alerts_channel_id: guild.safety_alerts_channel_id(),
+            splash: guild.splash().map(ToOwned::to_owned),
+            stage_instances,
+            stickers,
+            system_channel_flags: guild.system_channel_flags(),
+            system_channel_id: guild.system_channel_id(),
+            threads,
+            unavailable: Some(false),
+            vanity_url_code: guild.vanity_url_code().map(ToString::to_string),
+            verification_level: guild.verification_level(),
+            voice_states,
+            widget_channel_id: guild.widget_channel_id(),
+            widget_enabled: guild.widget_enabled(),
+        }
+    }
+
+    /// Get a guild with its channels, roles, emojis and other resources, but
+    /// without members, presences and voice states.
+    pub fn get_guild(&self, guild_id: Id<GuildMarker>) -> Option<Guild> {
+        let guild = self.0.guild(guild_id)?;
+
+        if guild.unavailable() == Some(true) {
+            return None;
+        }
+
+        Some(self.guild(&guild, Intents::empty(), None))
+    }
+
+    /// Get the `GUILD_CREATE`/`GUILD_DELETE` payloads for all guilds, containing
+    /// only the data that Discord would send for the given intents.
     pub fn get_guild_payloads<'a>(
         &'a self,
+        intents: Intents,
         sequence: &'a mut usize,
     ) -> impl Iterator<Item = String> + 'a {
-        self.0.iter().guilds().map(move |guild| {
+        // Without the GUILDS intent, Discord does not send GUILD_CREATEs at all
+        let guilds = intents
+            .contains(Intents::GUILDS)
+            .then(|| self.0.iter().guilds())
+            .into_iter()
+            .flatten();
+
+        let current_user_id = self.0.current_user().map(|user| user.id);
+
+        guilds.map(move |guild| {
             *sequence += 1;
 
             if guild.unavailable() == Some(true) {
@@ -346,73 +689,8 @@ impl Guilds {
                 })
                 .unwrap()
             } else {
-                let guild_channels = self.channels_in_guild(guild.id());
-                let presences = self.presences_in_guild(guild.id());
-                let emojis = self.emojis_in_guild(guild.id());
-                let members = self.members_in_guild(guild.id());
-                let roles = self.roles_in_guild(guild.id());
-                let scheduled_events = self.scheduled_events_in_guild(guild.id());
-                let stage_instances = self.stage_instances_in_guild(guild.id());
-                let stickers = self.stickers_in_guild(guild.id());
-                let voice_states = self.voice_states_in_guild(guild.id());
-                let threads = self.threads_in_guild(guild.id());
-
-                let new_guild = Guild {
-                    afk_channel_id: guild.afk_channel_id(),
-                    afk_timeout: guild.afk_timeout(),
-                    application_id: guild.application_id(),
-                    approximate_member_count: None, // Only present in with_counts HTTP endpoint
-                    banner: guild.banner().map(ToOwned::to_owned),
-                    approximate_presence_count: None, // Only present in with_counts HTTP endpoint
-                    channels: guild_channels,
-                    default_message_notifications: guild.default_message_notifications(),
-                    description: guild.description().map(ToString::to_string),
-                    discovery_splash: guild.discovery_splash().map(ToOwned::to_owned),
-                    emojis,
-                    explicit_content_filter: guild.explicit_content_filter(),
-                    features: guild.features().cloned().collect(),
-                    guild_scheduled_events: scheduled_events,
-                    icon: guild.icon().map(ToOwned::to_owned),
-                    id: guild.id(),
-                    joined_at: guild.joined_at(),
-                    large: guild.large(),
-                    max_members: guild.max_members(),
-                    max_presences: guild.max_presences(),
-                    max_stage_video_channel_users: guild.max_stage_video_channel_users(),
-                    max_video_channel_users: guild.max_video_channel_users(),
-                    member_count: guild.member_count(),
-                    members,
-                    mfa_level: guild.mfa_level(),
-                    name: guild.name().to_string(),
-                    nsfw_level: guild.nsfw_level(),
-                    owner_id: guild.owner_id(),
-                    owner: guild.owner(),
-                    permissions: guild.permissions(),
-                    public_updates_channel_id: guild.public_updates_channel_id(),
-                    preferred_locale: guild.preferred_locale().to_string(),
-                    premium_progress_bar_enabled: guild.premium_progress_bar_enabled(),
-                    premium_subscription_count: guild.premium_subscription_count(),
-                    premium_tier: guild.premium_tier(),
-                    presences,
-                    roles,
-                    rules_channel_id: guild.rules_channel_id(),
-                    safety_alerts_channel_id: guild.safety_alerts_channel_id(),
-                    splash: guild.splash().map(ToOwned::to_owned),
-                    stage_instances,
-                    stickers,
-                    system_channel_flags: guild.system_channel_flags(),
-                    system_channel_id: guild.system_channel_id(),
-                    threads,
-                    unavailable: Some(false),
-                    vanity_url_code: guild.vanity_url_code().map(ToString::to_string),
-                    verification_level: guild.verification_level(),
-                    voice_states,
-                    widget_channel_id: guild.widget_channel_id(),
-                    widget_enabled: guild.widget_enabled(),
-                };
-
                 to_string(&Payload {
-                    d: new_guild,
+                    d: self.guild(&guild, intents, current_user_id),
                     op: OpCode::Dispatch,
                     t: "GUILD_CREATE",
                     s: *sequence,
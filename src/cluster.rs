@@ -0,0 +1,199 @@
+use tokio::time::{interval, timeout};
+use tracing::{debug, info, warn};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_gateway::{Config, ConfigBuilder, Shard, ShardId};
+use twilight_http::Client;
+
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    cache,
+    config::{self, CONFIG},
+    dispatch, groups,
+    persistence::SavedShard,
+    presence::Presence,
+    ratelimit::Commands,
+    responses::Responses,
+    state::{self, Inner, State},
+    voice::Voice,
+};
+
+/// Create the shards in `shard_ids` of `shard_count` and start relaying
+/// their events.
+///
+/// Shards that have a session in `saved_shards` resume it instead of
+/// identifying.
+pub fn start(
+    config: &Config,
+    shard_count: u32,
+    shard_ids: Range<u32>,
+    saved_shards: &mut HashMap<u32, SavedShard>,
+) -> Arc<Inner> {
+    // New shards are created with the settings as they are now
+    let current_config = config::current();
+
+    let mut shards = Vec::with_capacity(shard_ids.len());
+    let mut gateway_shards = Vec::with_capacity(shards.capacity());
+
+    for shard_id in shard_ids {
+        let mut builder = ConfigBuilder::from(config.clone());
+
+        if current_config.activity.is_some() {
+            builder = builder.presence(current_config.presence(shard_id));
+        }
+
+        let saved_shard = saved_shards.remove(&shard_id);
+
+        if let Some(saved_shard) = &saved_shard {
+            debug!("Resuming saved session for shard {shard_id}");
+            builder = builder.session(saved_shard.session.clone());
+
+            if let Some(resume_url) = saved_shard.resume_url.clone() {
+                builder = builder.resume_url(resume_url);
+            }
+        }
+
+        let shard = Shard::with_config(ShardId::new(shard_id, shard_count), builder.build());
+
+        let cache = Arc::new(
+            InMemoryCache::builder()
+                .resource_types(CONFIG.cache.clone().into())
+                .message_cache_size(0)
+                .build(),
+        );
+        let guild_cache = cache::Guilds::new(cache.clone());
+
+        let ready = state::Ready::new();
+
+        let shard_status = Arc::new(state::Shard {
+            id: shard_id,
+            sender: shard.sender(),
+            commands: Commands::new(shard.sender()),
+            responses: Responses::new(),
+            presence: Presence::new(),
+            voice: Voice::new(),
+            // To support multiple listeners on the same shard
+            // we need to make a broadcast channel with the events
+            events: state::Events::new(current_config.backpressure),
+            history: state::History::new(CONFIG.replay_buffer),
+            ready,
+            guilds: guild_cache,
+            groups: groups::Groups::new(),
+            status: Mutex::default(),
+        });
+
+        let restored = saved_shard.is_some();
+
+        if let Some(saved_shard) = saved_shard {
+            saved_shard.restore(&shard_status);
+        }
+
+        shards.push(shard_status);
+        gateway_shards.push((shard, restored));
+
+        debug!("Created shard {shard_id} of {shard_count} total");
+    }
+
+    let inner = Arc::new(Inner::new(shards, shard_count));
+
+    {
+        let mut dispatch_tasks = inner.dispatch_tasks.lock().unwrap();
+
+        for ((shard, restored), shard_status) in gateway_shards.into_iter().zip(&inner.shards) {
+            // Now pipe the events into the broadcast
+            // and handle state updates for the guild cache
+            // and set the ready event if received
+            dispatch_tasks.spawn(dispatch::events(
+                shard,
+                inner.clone(),
+                shard_status.clone(),
+                shard_status.id,
+                restored,
+            ));
+        }
+    }
+
+    inner
+}
+
+/// Time that each new shard gets to become READY when resharding, since
+/// shards identify one after another.
+const RESHARD_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Periodically check the shard count recommended by Discord and switch to a
+/// new set of shards when it grows.
+///
+/// The new shards are started in the background and replace the current ones
+/// once all of them are READY. If they take too long, they are shut down and
+/// the current shards are kept until the next check.
+pub async fn reshard(state: State, client: Arc<Client>, config: Config, check_interval: Duration) {
+    let mut interval = interval(check_interval);
+
+    // The first tick completes immediately
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let gateway = match client.gateway().authed().await {
+            Ok(response) => match response.model().await {
+                Ok(gateway) => gateway,
+                Err(e) => {
+                    warn!("Failed to deserialize recommended shard count: {e}");
+                    continue;
+                }
+            },
+            Err(e) => {
+                warn!("Failed to fetch recommended shard count: {e}");
+                continue;
+            }
+        };
+
+        let recommended = gateway.shards;
+        let shard_count = state.get().shard_count;
+
+        if recommended <= shard_count {
+            debug!("Recommended shard count is {recommended}, not resharding");
+            continue;
+        }
+
+        // Every new shard has to identify, so don't use up the identify budget
+        // that the current shards need if they have to identify again
+        let remaining = gateway.session_start_limit.remaining;
+
+        if remaining < recommended {
+            warn!(
+                "Only {remaining} session starts remaining, not resharding to {recommended} shards"
+            );
+            continue;
+        }
+
+        info!("Resharding from {shard_count} to {recommended} shards");
+
+        let inner = start(&config, recommended, 0..recommended, &mut HashMap::new());
+
+        let all_ready = async {
+            for shard in &inner.shards {
+                shard.ready.wait_until_ready().await;
+            }
+        };
+
+        if timeout(RESHARD_READY_TIMEOUT * recommended, all_ready)
+            .await
+            .is_err()
+        {
+            warn!("New shards did not become READY in time, keeping {shard_count} shards");
+            inner.retire();
+            continue;
+        }
+
+        info!("All {recommended} new shards are READY, switching over");
+
+        state.replace(inner).retire();
+    }
+}
@@ -0,0 +1,125 @@
+use bytes::Bytes;
+use flate2::{Compress, Compression, FlushCompress, Status};
+use zstd::stream::write::Encoder as ZstdEncoder;
+
+use std::io::Write;
+
+use crate::upgrade::query_param;
+
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Compression level for zstd, similar in speed to the zlib settings.
+const ZSTD_LEVEL: i32 = 1;
+
+/// Transport compression of a client connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportCompression {
+    None,
+    ZlibStream,
+    ZstdStream,
+}
+
+impl TransportCompression {
+    /// Parse the `compress` parameter of a websocket URL query.
+    pub fn from_query(query: &str) -> Self {
+        match query_param(query, "compress").as_deref() {
+            Some("zlib-stream") => Self::ZlibStream,
+            Some("zstd-stream") => Self::ZstdStream,
+            _ => Self::None,
+        }
+    }
+
+    /// Name of the compression as used in the query, if there is any.
+    pub const fn name(self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::ZlibStream => Some("zlib-stream"),
+            Self::ZstdStream => Some("zstd-stream"),
+        }
+    }
+}
+
+fn compress_full(compressor: &mut Compress, output: &mut Vec<u8>, input: &[u8]) {
+    let before_in = compressor.total_in() as usize;
+    while (compressor.total_in() as usize) - before_in < input.len() {
+        let offset = (compressor.total_in() as usize) - before_in;
+        match compressor
+            .compress_vec(&input[offset..], output, FlushCompress::None)
+            .unwrap()
+        {
+            Status::Ok => {}
+            Status::BufError => output.reserve(4096),
+            Status::StreamEnd => break,
+        }
+    }
+
+    while !output.ends_with(&TRAILER) {
+        output.reserve(5);
+        if compressor
+            .compress_vec(&[], output, FlushCompress::Sync)
+            .unwrap()
+            == Status::StreamEnd
+        {
+            break;
+        }
+    }
+}
+
+/// Streaming compressor for all messages sent to a client.
+pub enum Encoder {
+    Zlib { compress: Compress, buffer: Vec<u8> },
+    Zstd(ZstdEncoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    /// Create an encoder for the given transport compression, if any.
+    pub fn new(compression: TransportCompression) -> Option<Self> {
+        match compression {
+            TransportCompression::None => None,
+            // Initialize a zlib encoder with similar settings to Discord's
+            TransportCompression::ZlibStream => Some(Self::Zlib {
+                compress: Compress::new(Compression::fast(), true),
+                buffer: Vec::with_capacity(32 * 1024),
+            }),
+            // This only fails if zstd fails to allocate its context
+            TransportCompression::ZstdStream => Some(Self::Zstd(
+                ZstdEncoder::new(Vec::with_capacity(32 * 1024), ZSTD_LEVEL).unwrap(),
+            )),
+        }
+    }
+
+    const fn algorithm(&self) -> &'static str {
+        match self {
+            Self::Zlib { .. } => "zlib-stream",
+            Self::Zstd(_) => "zstd-stream",
+        }
+    }
+
+    /// Compress a message and flush it, so that the client can decompress it
+    /// without waiting for further data.
+    pub fn encode(&mut self, input: &[u8]) -> Bytes {
+        let output = match self {
+            Self::Zlib { compress, buffer } => {
+                buffer.clear();
+                compress_full(compress, buffer, input);
+
+                Bytes::from(buffer.clone())
+            }
+            Self::Zstd(encoder) => {
+                // Writing to a Vec can not fail
+                encoder.get_mut().clear();
+                encoder.write_all(input).unwrap();
+                encoder.flush().unwrap();
+
+                Bytes::from(encoder.get_ref().clone())
+            }
+        };
+
+        if !input.is_empty() {
+            metrics::histogram!("gateway_compression_ratio", "algorithm" => self.algorithm())
+                .record(output.len() as f64 / input.len() as f64);
+        }
+
+        output
+    }
+}
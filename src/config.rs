@@ -1,5 +1,5 @@
 use futures_util::StreamExt;
-use inotify::{Inotify, WatchMask};
+use inotify::{EventStream, Inotify, WatchMask};
 use serde::Deserialize;
 #[cfg(not(feature = "simd-json"))]
 use serde_json::Error as JsonError;
@@ -8,18 +8,27 @@ use simd_json::Error as JsonError;
 use tracing_subscriber::{filter::LevelFilter, reload};
 use twilight_cache_inmemory::ResourceType;
 use twilight_gateway::{EventTypeFlags, Intents};
-use twilight_model::gateway::presence::{Activity, Status};
+use twilight_model::gateway::{
+    payload::outgoing::update_presence::UpdatePresencePayload,
+    presence::{Activity, Status},
+};
 
 use std::{
     env::var,
     fmt::{Display, Formatter, Result as FmtResult},
     fs::read_to_string,
+    io,
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
     process::exit,
     str::FromStr,
-    sync::LazyLock,
+    sync::{Arc, LazyLock, RwLock},
+    time::Duration,
 };
 
-#[derive(Deserialize)]
+use crate::state::State;
+
+#[derive(Deserialize, Clone)]
 pub struct Config {
     #[serde(default = "default_log_level")]
     pub log_level: String,
@@ -38,18 +47,249 @@ pub struct Config {
     pub activity: Option<Activity>,
     #[serde(default = "default_status")]
     pub status: Status,
+    #[serde(default)]
+    pub presence_policy: PresencePolicy,
+    #[serde(default)]
+    pub identify_presence: bool,
     #[serde(default = "default_backpressure")]
     pub backpressure: usize,
+    #[serde(default = "default_replay_buffer")]
+    pub replay_buffer: usize,
+    #[serde(default = "default_resume_window")]
+    pub resume_window: u64,
+    #[serde(default)]
+    pub lag_policy: LagPolicy,
+    #[serde(default)]
+    pub client_queue: ClientQueue,
+    #[serde(default)]
+    pub command_policy: CommandPolicy,
     #[serde(default = "default_validate_token")]
     pub validate_token: bool,
+    #[serde(default = "default_heartbeat_grace")]
+    pub heartbeat_grace: u64,
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval: u64,
+    #[serde(default)]
+    pub credentials: Vec<Credential>,
+    #[serde(default)]
+    pub state_file: Option<String>,
+    #[serde(default)]
+    pub reshard_interval: Option<u64>,
+    #[serde(default)]
+    pub admin: Option<Admin>,
+    #[serde(default)]
+    pub tls: Option<Tls>,
+    #[serde(default)]
+    pub unix_socket: Option<UnixSocket>,
     #[serde(default)]
     pub twilight_http_proxy: Option<String>,
     pub externally_accessible_url: String,
     #[serde(default)]
     pub cache: Cache,
+    #[serde(default)]
+    pub cache_api: bool,
+}
+
+impl Config {
+    /// Time in which a disconnected client may resume its session.
+    pub const fn resume_window(&self) -> Duration {
+        Duration::from_secs(self.resume_window)
+    }
+
+    /// Time that a client may be late with its heartbeat before it is disconnected.
+    pub const fn heartbeat_grace(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_grace)
+    }
+
+    /// Interval in which clients are sent websocket pings, if enabled.
+    pub fn ping_interval(&self) -> Option<Duration> {
+        (self.ping_interval != 0).then(|| Duration::from_secs(self.ping_interval))
+    }
+
+    /// Interval in which to check whether Discord recommends more shards.
+    pub fn reshard_interval(&self) -> Option<Duration> {
+        self.reshard_interval.map(Duration::from_secs)
+    }
+
+    /// Presence of a shard, with `{{shard}}` in the activity name replaced by its ID.
+    pub fn presence(&self, shard_id: u32) -> UpdatePresencePayload {
+        let activities = self
+            .activity
+            .clone()
+            .map(|mut activity| {
+                activity.name = activity.name.replace("{{shard}}", &shard_id.to_string());
+                activity
+            })
+            .into_iter()
+            .collect();
+
+        UpdatePresencePayload {
+            activities,
+            afk: false,
+            since: None,
+            status: self.status,
+        }
+    }
+
+    /// Names of the settings that differ from `other` and only apply on startup.
+    fn changed_startup_settings(&self, other: &Self) -> Vec<&'static str> {
+        [
+            ("token", self.token != other.token),
+            ("intents", self.intents != other.intents),
+            ("port", self.port != other.port),
+            ("shards", self.shards != other.shards),
+            ("shard_start", self.shard_start != other.shard_start),
+            ("shard_end", self.shard_end != other.shard_end),
+            ("replay_buffer", self.replay_buffer != other.replay_buffer),
+            ("state_file", self.state_file != other.state_file),
+            (
+                "reshard_interval",
+                self.reshard_interval != other.reshard_interval,
+            ),
+            (
+                "twilight_http_proxy",
+                self.twilight_http_proxy != other.twilight_http_proxy,
+            ),
+            ("cache", self.cache != other.cache),
+            ("tls", self.tls != other.tls),
+            ("unix_socket", self.unix_socket != other.unix_socket),
+            (
+                "admin",
+                self.admin.as_ref().map(|admin| (admin.address, admin.port))
+                    != other
+                        .admin
+                        .as_ref()
+                        .map(|admin| (admin.address, admin.port)),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Admin {
+    #[serde(default = "default_admin_address")]
+    pub address: IpAddr,
+    pub port: u16,
+    pub token: String,
+}
+
+/// Which client decides the presence of a shard when several of them set one.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresencePolicy {
+    /// The most recent update from any client.
+    #[default]
+    Latest,
+    /// The first client that set a presence, for as long as its session exists.
+    First,
+    /// Only the configured `activity` and `status`, updates from clients are
+    /// ignored.
+    Config,
+}
+
+/// What happens to a client that falls so far behind that it misses events.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LagPolicy {
+    /// Keep sending events, the missed ones are lost.
+    #[default]
+    Ignore,
+    /// Disconnect the client so that it resumes and gets the missed events
+    /// replayed.
+    Resume,
+    /// Invalidate the session so that the client identifies again.
+    Invalidate,
+}
+
+/// What happens when the queue of messages for a client is full.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePolicy {
+    /// Wait until the client catches up, which makes it lag behind the shard
+    /// if it takes too long.
+    #[default]
+    Wait,
+    /// Disconnect the client so that it resumes.
+    Disconnect,
+}
+
+/// What happens to a command from a client that exceeds the rate limit of
+/// its shard.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandPolicy {
+    /// Send the command once the rate limit allows it.
+    #[default]
+    Queue,
+    /// Disconnect the client like Discord does.
+    Reject,
 }
 
 #[derive(Deserialize, Clone)]
+pub struct ClientQueue {
+    #[serde(default = "default_queue_messages")]
+    pub messages: usize,
+    #[serde(default = "default_queue_bytes")]
+    pub bytes: usize,
+    #[serde(default)]
+    pub policy: QueuePolicy,
+}
+
+impl Default for ClientQueue {
+    fn default() -> Self {
+        Self {
+            messages: default_queue_messages(),
+            bytes: default_queue_bytes(),
+            policy: QueuePolicy::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Credential {
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub shard_start: Option<u32>,
+    #[serde(default)]
+    pub shard_end: Option<u32>,
+    #[serde(default)]
+    pub opcodes: Option<Vec<u8>>,
+}
+
+impl Credential {
+    /// Whether clients with this credential may connect to a shard.
+    pub fn allows_shard(&self, shard_id: u32) -> bool {
+        self.shard_start.is_none_or(|start| shard_id >= start)
+            && self.shard_end.is_none_or(|end| shard_id < end)
+    }
+
+    /// Whether clients with this credential may send a gateway command.
+    pub fn allows_opcode(&self, op: u8) -> bool {
+        self.opcodes
+            .as_ref()
+            .is_none_or(|opcodes| opcodes.contains(&op))
+    }
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+pub struct UnixSocket {
+    pub path: String,
+    /// File mode of the socket in octal, such as `660`.
+    #[serde(default)]
+    pub permissions: Option<String>,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq)]
+pub struct Tls {
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Deserialize, Clone, PartialEq, Eq)]
 pub struct Cache {
     pub channels: bool,
     pub presences: bool,
@@ -198,6 +438,10 @@ const fn default_port() -> u16 {
     7878
 }
 
+const fn default_admin_address() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
 fn token_fallback() -> String {
     if let Ok(token) = var("TOKEN") {
         token
@@ -215,10 +459,34 @@ const fn default_backpressure() -> usize {
     100
 }
 
+const fn default_replay_buffer() -> usize {
+    1000
+}
+
+const fn default_resume_window() -> u64 {
+    180
+}
+
 const fn default_validate_token() -> bool {
     true
 }
 
+const fn default_queue_messages() -> usize {
+    10_000
+}
+
+const fn default_queue_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+const fn default_heartbeat_grace() -> u64 {
+    10
+}
+
+const fn default_ping_interval() -> u64 {
+    15
+}
+
 pub enum Error {
     InvalidConfig(JsonError),
     NotFound(String),
@@ -260,36 +528,87 @@ pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
     }
 });
 
-pub async fn watch_config_changes<S>(reload_handle: reload::Handle<LevelFilter, S>) {
-    let Ok(inotify) = Inotify::init() else {
-        tracing::error!("Failed to initialize inotify, log-levels cannot be reloaded on the fly");
-        return;
-    };
+/// The configuration as it was last loaded, including changes made while the
+/// proxy is running.
+///
+/// Settings that only apply on startup are read from [`CONFIG`] instead.
+static CURRENT: LazyLock<RwLock<Arc<Config>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(CONFIG.clone())));
 
-    if inotify
-        .watches()
-        .add("config.json", WatchMask::MODIFY)
-        .is_err()
-    {
-        tracing::error!("Failed to add inotify watch, log-levels cannot be reloaded on the fly");
-        return;
-    };
+/// Get the configuration as it was last loaded.
+pub fn current() -> Arc<Config> {
+    CURRENT.read().unwrap().clone()
+}
+
+/// Apply a modified configuration to the running proxy.
+fn reload<S>(config: Config, reload_handle: &reload::Handle<LevelFilter, S>, state: &State) {
+    let previous = current();
+
+    let _ = reload_handle.modify(|filter| {
+        *filter = LevelFilter::from_str(&config.log_level).unwrap_or(LevelFilter::INFO);
+    });
+
+    let presence_locked = config.presence_policy == PresencePolicy::Config
+        && previous.presence_policy != PresencePolicy::Config;
+
+    if config.activity != previous.activity || config.status != previous.status || presence_locked {
+        for shard in &state.get().shards {
+            // The configured presence replaces the ones set by clients
+            shard.presence.reset();
+
+            if !shard.commands.update_presence(config.presence(shard.id)) {
+                tracing::warn!("[Shard {}] Failed to update presence", shard.id);
+            }
+        }
+
+        tracing::info!("Updated the presence of all shards");
+    }
+
+    if config.backpressure != previous.backpressure {
+        for shard in &state.get().shards {
+            shard.events.resize(Some(config.backpressure));
+        }
+    }
+
+    for name in CONFIG.changed_startup_settings(&config) {
+        tracing::warn!("Config option {name} was modified, but requires a restart to apply");
+    }
+
+    *CURRENT.write().unwrap() = Arc::new(config);
+}
+
+/// Watch files or directories for changes with inotify.
+pub fn watch_paths<P: AsRef<Path>>(
+    paths: &[P],
+    mask: WatchMask,
+) -> io::Result<EventStream<[u8; 4096]>> {
+    let inotify = Inotify::init()?;
+
+    for path in paths {
+        inotify.watches().add(path, mask)?;
+    }
 
     tracing::debug!("Inotify is initialized");
 
-    let buffer = [0u8; 4096];
-    // This method never returns Err
-    let mut events = inotify.into_event_stream(buffer).unwrap();
+    inotify.into_event_stream([0u8; 4096])
+}
+
+pub async fn watch_config_changes<S>(reload_handle: reload::Handle<LevelFilter, S>, state: State) {
+    let mut events = match watch_paths(&["config.json"], WatchMask::MODIFY) {
+        Ok(events) => events,
+        Err(e) => {
+            tracing::error!("Failed to watch config, it cannot be reloaded on the fly: {e}");
+            return;
+        }
+    };
 
     while let Some(Ok(_)) = events.next().await {
-        // This currently only supports reloading log-levels
-        if let Ok(config) = load("config.json") {
-            let _ = reload_handle.modify(|filter| {
-                *filter = LevelFilter::from_str(&config.log_level).unwrap_or(LevelFilter::INFO);
-            });
-            tracing::info!("Config was modified, reloaded log-level");
-        } else {
-            tracing::error!("Config was modified, but failed to reload");
+        match load("config.json") {
+            Ok(config) => {
+                reload(config, &reload_handle, &state);
+                tracing::info!("Config was modified, reloaded");
+            }
+            Err(e) => tracing::error!("Config was modified, but failed to reload: {e}"),
         }
     }
 }
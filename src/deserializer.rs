@@ -114,6 +114,6 @@ impl<'a> GatewayEvent<'a> {
         let range = from..from + to;
         let clean = input.get(range.clone())?;
 
-        T::from_str(clean).ok().map(|int| (int, range))
+        T::from_str(clean.trim()).ok().map(|int| (int, range))
     }
 }
@@ -1,41 +1,89 @@
 use futures_util::StreamExt;
 use itoa::Buffer;
 #[cfg(feature = "simd-json")]
-use simd_json::prelude::ValueAsMutArray;
-use tokio::{sync::broadcast, time::Instant};
-use tracing::{debug, trace};
+use simd_json::prelude::{ValueAsMutArray, ValueAsScalar, ValueObjectAccess};
+use tokio::time::Instant;
+use tracing::{debug, trace, warn};
 use twilight_gateway::{
-    parse, Event, EventTypeFlags, Message, Shard, ShardState as ConnectionState,
+    parse, Event, EventTypeFlags, Intents, Message, Shard, ShardState as ConnectionState,
 };
 use twilight_model::gateway::event::GatewayEvent as TwilightGatewayEvent;
 
 use std::{
+    mem,
     sync::{atomic::Ordering, Arc},
     time::Duration,
 };
 
 use crate::{
-    config::CONFIG,
+    config::{self, CONFIG},
     deserializer::{EventTypeInfo, GatewayEvent, SequenceInfo},
+    groups, intents,
     model::Ready,
-    state::Shard as ShardState,
+    persistence::SavedShard,
+    state::{Inner, Shard as ShardState, Status},
     SHUTDOWN,
 };
 
-pub type BroadcastMessage = (String, Option<SequenceInfo>);
+/// A dispatch that is broadcast to all clients of a shard.
+#[derive(Clone)]
+pub struct BroadcastMessage {
+    /// Index of this event in the shard's history.
+    pub index: u64,
+    /// Raw JSON payload as received from Discord.
+    pub payload: String,
+    /// Position of the sequence number in the payload.
+    pub sequence: Option<SequenceInfo>,
+    /// Intents of which a client needs at least one to receive this event.
+    pub intents: Intents,
+    /// IDs of the worker group members that receive this event.
+    pub owners: Vec<String>,
+    /// ID of the only session that receives this event, if it is a response
+    /// to a command of that session.
+    pub target: Option<String>,
+}
+
+impl BroadcastMessage {
+    /// Create a dispatch that only the given session receives.
+    pub fn targeted(session_id: &str, payload: String) -> Self {
+        let sequence = GatewayEvent::from_json(&payload).and_then(|event| event.into_parts().1);
+
+        Self {
+            // Assigned by the history
+            index: 0,
+            payload,
+            sequence,
+            intents: Intents::empty(),
+            owners: Vec::new(),
+            target: Some(session_id.to_owned()),
+        }
+    }
+}
 
 const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
 
+/// Relay the events of a shard to its clients until the proxy shuts down.
+///
+/// If `restored` is true, the shard resumes a saved session whose READY and
+/// cache state were already restored.
+///
+/// Returns the saved session of the shard if it shut down cleanly.
+#[allow(clippy::too_many_lines)]
 pub async fn events(
     mut shard: Shard,
+    state: Arc<Inner>,
     shard_state: Arc<ShardState>,
     shard_id: u32,
-    broadcast_tx: broadcast::Sender<BroadcastMessage>,
-) {
+    mut restored: bool,
+) -> Option<SavedShard> {
     // This method only wants to relay events while the shard is in a READY state
     // Therefore, we only put events in the queue while we are connected and READY
     let mut is_ready = false;
 
+    // Whether the shard has been READY before, so that a new READY means
+    // that the shard had to identify again
+    let mut has_been_ready = restored;
+
     let mut buffer = Buffer::new();
     let shard_id_str = buffer.format(shard_id).to_owned();
 
@@ -54,9 +102,17 @@ pub async fn events(
             last_metrics_update = now;
         }
 
-        let payload = match shard.next().await {
+        let mut payload = match shard.next().await {
             Some(Ok(Message::Text(payload))) => payload,
-            Some(Ok(Message::Close(_))) if SHUTDOWN.load(Ordering::Relaxed) => return,
+            Some(Ok(Message::Close(_))) if SHUTDOWN.load(Ordering::Relaxed) => {
+                let resume_url = shard.resume_url().map(ToOwned::to_owned);
+
+                return SavedShard::new(&shard_state, shard.session()?.clone(), resume_url);
+            }
+            Some(Ok(Message::Close(_))) if state.is_retired() => {
+                debug!("[Shard {shard_id}] Shard was replaced after resharding");
+                return None;
+            }
             Some(Ok(Message::Close(_))) => {
                 tracing::info!("Shard {shard_id} got a close message");
 
@@ -68,7 +124,7 @@ pub async fn events(
             }
             None => {
                 tracing::warn!("Shard {shard_id} stream closed");
-                return;
+                return None;
             }
         };
 
@@ -101,28 +157,103 @@ pub async fn events(
                     }
                 }
 
-                // Override resume_gateway_url with the external URI of the proxy
-                ready.d.insert(
-                    String::from("resume_gateway_url"),
-                    CONFIG.externally_accessible_url.clone().into(),
-                );
+                if let Some(user_id) = ready
+                    .d
+                    .get("user")
+                    .and_then(|user| user.get("id"))
+                    .and_then(|id| id.as_str())
+                    .and_then(|id| id.parse().ok())
+                {
+                    shard_state.voice.set_user_id(user_id);
+                }
+
+                // The saved session could not be resumed, so the restored
+                // cache is outdated
+                if restored {
+                    debug!("[Shard {shard_id}] Saved session was not resumed, clearing cache");
+                    shard_state.guilds.clear();
+                    restored = false;
+                }
 
                 // We don't care if it was already set
                 // since this data is timeless
                 shard_state.ready.set_ready(ready.d);
                 is_ready = true;
+
+                // Client sessions can not be resumed across a new upstream session
+                if has_been_ready {
+                    debug!("[Shard {shard_id}] Shard identified again, invalidating sessions");
+                    state.invalidate_sessions(shard_id);
+                    shard_state.voice.clear();
+                    reapply_presence(&shard_state);
+                }
+
+                has_been_ready = true;
             } else if event_name == "RESUMED" {
                 is_ready = true;
             } else if op.0 == 0 && is_ready {
                 // We only want to relay dispatchable events, not RESUMEs and not READY
                 // because we fake a READY event
-                let payload_copy = payload.clone();
+                let mut payload_copy = payload.clone();
                 trace!("[Shard {shard_id}] Sending payload to clients: {payload_copy:?}",);
 
-                let _res = broadcast_tx.send((payload_copy, sequence));
+                let guild_id = groups::guild_id(event_name, &payload_copy);
+
+                // Voice events of the bot user and responses to commands only go
+                // to the session that asked for them, with the nonce of the client
+                // put back
+                let target = shard_state
+                    .voice
+                    .route(event_name, &payload_copy, guild_id)
+                    .or_else(|| {
+                        shard_state
+                            .responses
+                            .route(event_name, &mut payload_copy, guild_id)
+                    });
+
+                let sequence = if target.is_some() {
+                    GatewayEvent::from_json(&payload_copy).and_then(|event| event.into_parts().1)
+                } else {
+                    sequence
+                };
+
+                // Events without a guild are spread between worker group
+                // members by their sequence number instead
+                let partition_key = guild_id
+                    .or_else(|| sequence.as_ref().map(|SequenceInfo(seq, _)| *seq))
+                    .unwrap_or_default();
+
+                let message = BroadcastMessage {
+                    // Assigned by the history
+                    index: 0,
+                    intents: intents::required(event_name, &payload_copy),
+                    owners: shard_state.groups.owners(partition_key),
+                    payload: payload_copy,
+                    sequence,
+                    target,
+                };
+
+                // Apply the event to the cache before it is added to the
+                // history, so that clients which build their READY and
+                // GUILD_CREATEs from the cache can not miss it
+                if let Ok(Some(TwilightGatewayEvent::Dispatch(_, event))) =
+                    parse(mem::take(&mut payload), event_type_flags)
+                {
+                    shard_state.guilds.update(Event::from(event));
+                }
+
+                // Remember the event before broadcasting it so that clients
+                // can always find events they missed in the history
+                let message = shard_state.history.push(message);
+                shard_state.events.send(&message);
             }
         }
 
+        // Relayed dispatches were already applied to the cache
+        if payload.is_empty() {
+            continue;
+        }
+
         if let Ok(Some(event)) = parse(payload, event_type_flags) {
             match event {
                 TwilightGatewayEvent::Dispatch(_, event) => {
@@ -144,19 +275,43 @@ pub async fn events(
     }
 }
 
+/// Send the presence of a shard again after it identified with the presence
+/// that it was created with.
+fn reapply_presence(shard_state: &ShardState) {
+    let current_config = config::current();
+
+    let Some(presence) = shard_state.presence.get().or_else(|| {
+        current_config
+            .activity
+            .is_some()
+            .then(|| current_config.presence(shard_state.id))
+    }) else {
+        return;
+    };
+
+    if !shard_state.commands.update_presence(presence) {
+        warn!("[Shard {}] Failed to apply presence again", shard_state.id);
+    }
+}
+
 pub fn update_shard_statistics(
     shard_id: &str,
     shard_state: &Arc<ShardState>,
     connection_status: ConnectionState,
     latencies: &[Duration],
 ) {
+    *shard_state.status.lock().unwrap() = Status {
+        connection: connection_status,
+        latency: latencies.first().copied(),
+    };
+
     // There is no way around this, sadly
     let connection_status = match connection_status {
         ConnectionState::Active => 4.0,
         ConnectionState::Disconnected { .. } => 1.0,
         ConnectionState::Identifying => 2.0,
         ConnectionState::Resuming => 3.0,
-        ConnectionState::FatallyClosed { .. } => 0.0,
+        ConnectionState::FatallyClosed => 0.0,
     };
 
     let latency = latencies.first().map_or(f64::NAN, Duration::as_secs_f64);
@@ -0,0 +1,528 @@
+use serde::de::{DeserializeSeed, Deserializer, Error as DeError, MapAccess, SeqAccess, Visitor};
+
+use std::fmt::{Formatter, Result as FmtResult, Write};
+
+use crate::upgrade::query_param;
+
+const FORMAT_VERSION: u8 = 131;
+const NEW_FLOAT_EXT: u8 = 70;
+const SMALL_INTEGER_EXT: u8 = 97;
+const INTEGER_EXT: u8 = 98;
+const ATOM_EXT: u8 = 100;
+const SMALL_TUPLE_EXT: u8 = 104;
+const LARGE_TUPLE_EXT: u8 = 105;
+const NIL_EXT: u8 = 106;
+const STRING_EXT: u8 = 107;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const SMALL_BIG_EXT: u8 = 110;
+const LARGE_BIG_EXT: u8 = 111;
+const SMALL_ATOM_EXT: u8 = 115;
+const MAP_EXT: u8 = 116;
+const ATOM_UTF8_EXT: u8 = 118;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+/// Maximum nesting of incoming terms, Discord payloads are far shallower.
+const MAX_DEPTH: usize = 32;
+
+/// Payload encoding of a client connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Etf,
+}
+
+impl Encoding {
+    /// Parse the `encoding` parameter of a websocket URL query.
+    pub fn from_query(query: &str) -> Self {
+        match query_param(query, "encoding").as_deref() {
+            Some("etf") => Self::Etf,
+            _ => Self::Json,
+        }
+    }
+}
+
+fn write_atom<E: DeError>(output: &mut Vec<u8>, atom: &str) -> Result<(), E> {
+    if let Ok(len) = u8::try_from(atom.len()) {
+        output.push(SMALL_ATOM_UTF8_EXT);
+        output.push(len);
+    } else {
+        let len = u16::try_from(atom.len()).map_err(|_| E::custom("atom is too long"))?;
+
+        output.push(ATOM_UTF8_EXT);
+        output.extend_from_slice(&len.to_be_bytes());
+    }
+
+    output.extend_from_slice(atom.as_bytes());
+
+    Ok(())
+}
+
+/// Whether the values of an object key are snowflakes, which Discord sends as
+/// integers over ETF.
+fn is_snowflake_key(key: &str) -> bool {
+    // These are strings chosen by clients or Discord, not snowflakes
+    if matches!(key, "custom_id" | "session_id") {
+        return false;
+    }
+
+    key == "id" || key.ends_with("_id") || key.ends_with("_ids")
+}
+
+fn write_integer(output: &mut Vec<u8>, value: i128) {
+    if let Ok(small) = u8::try_from(value) {
+        output.push(SMALL_INTEGER_EXT);
+        output.push(small);
+    } else if let Ok(integer) = i32::try_from(value) {
+        output.push(INTEGER_EXT);
+        output.extend_from_slice(&integer.to_be_bytes());
+    } else {
+        let digits = value.unsigned_abs().to_le_bytes();
+        let len = digits
+            .iter()
+            .rposition(|digit| *digit != 0)
+            .map_or(0, |pos| pos + 1);
+
+        output.push(SMALL_BIG_EXT);
+        output.push(len as u8);
+        output.push(u8::from(value < 0));
+        output.extend_from_slice(&digits[..len]);
+    }
+}
+
+/// Writes the ETF representation of any JSON value it deserializes.
+///
+/// Like Discord, object keys are written as atoms, snowflakes as integers,
+/// other strings as binaries and `null` as the atom `nil`.
+struct Transcoder<'a> {
+    output: &'a mut Vec<u8>,
+    /// Whether strings are snowflakes because of the key they belong to.
+    snowflake: bool,
+}
+
+impl<'a> Transcoder<'a> {
+    const fn new(output: &'a mut Vec<u8>) -> Self {
+        Self {
+            output,
+            snowflake: false,
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for Transcoder<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Transcoder<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str("any JSON value")
+    }
+
+    fn visit_bool<E: DeError>(self, v: bool) -> Result<(), E> {
+        write_atom(self.output, if v { "true" } else { "false" })
+    }
+
+    fn visit_i64<E: DeError>(self, v: i64) -> Result<(), E> {
+        write_integer(self.output, v.into());
+        Ok(())
+    }
+
+    fn visit_u64<E: DeError>(self, v: u64) -> Result<(), E> {
+        write_integer(self.output, v.into());
+        Ok(())
+    }
+
+    fn visit_f64<E: DeError>(self, v: f64) -> Result<(), E> {
+        self.output.push(NEW_FLOAT_EXT);
+        self.output.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn visit_str<E: DeError>(self, v: &str) -> Result<(), E> {
+        if self.snowflake {
+            if let Ok(snowflake) = v.parse::<u64>() {
+                write_integer(self.output, snowflake.into());
+                return Ok(());
+            }
+        }
+
+        let len = u32::try_from(v.len()).map_err(|_| E::custom("string is too long"))?;
+
+        self.output.push(BINARY_EXT);
+        self.output.extend_from_slice(&len.to_be_bytes());
+        self.output.extend_from_slice(v.as_bytes());
+        Ok(())
+    }
+
+    fn visit_unit<E: DeError>(self) -> Result<(), E> {
+        write_atom(self.output, "nil")
+    }
+
+    fn visit_none<E: DeError>(self) -> Result<(), E> {
+        self.visit_unit()
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let output = self.output;
+        let start = output.len();
+
+        // The length is filled in once all elements are written
+        output.push(LIST_EXT);
+        output.extend_from_slice(&[0; 4]);
+
+        let mut len: u32 = 0;
+
+        // Elements of lists of snowflakes are snowflakes as well
+        while seq
+            .next_element_seed(Transcoder {
+                output: &mut *output,
+                snowflake: self.snowflake,
+            })?
+            .is_some()
+        {
+            len += 1;
+        }
+
+        if len == 0 {
+            // Empty lists are just NIL
+            output.truncate(start);
+        } else {
+            output[start + 1..start + 5].copy_from_slice(&len.to_be_bytes());
+        }
+
+        output.push(NIL_EXT);
+
+        Ok(())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let output = self.output;
+        let start = output.len();
+
+        // The arity is filled in once all pairs are written
+        output.push(MAP_EXT);
+        output.extend_from_slice(&[0; 4]);
+
+        let mut arity: u32 = 0;
+
+        while let Some(snowflake) = map.next_key_seed(KeyTranscoder(&mut *output))? {
+            map.next_value_seed(Transcoder {
+                output: &mut *output,
+                snowflake,
+            })?;
+            arity += 1;
+        }
+
+        output[start + 1..start + 5].copy_from_slice(&arity.to_be_bytes());
+
+        Ok(())
+    }
+}
+
+/// Writes JSON object keys as atoms and tells whether their values are
+/// snowflakes.
+struct KeyTranscoder<'a>(&'a mut Vec<u8>);
+
+impl<'de> DeserializeSeed<'de> for KeyTranscoder<'_> {
+    type Value = bool;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<bool, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl Visitor<'_> for KeyTranscoder<'_> {
+    type Value = bool;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str("a JSON object key")
+    }
+
+    fn visit_str<E: DeError>(self, v: &str) -> Result<bool, E> {
+        write_atom(self.0, v)?;
+        Ok(is_snowflake_key(v))
+    }
+}
+
+/// Transcode a JSON payload to ETF.
+#[cfg(feature = "simd-json")]
+pub fn json_to_etf(json: &[u8]) -> Option<Vec<u8>> {
+    let mut input = json.to_vec();
+    let mut deserializer = simd_json::Deserializer::from_slice(&mut input).ok()?;
+
+    let mut output = Vec::with_capacity(json.len());
+    output.push(FORMAT_VERSION);
+    Transcoder::new(&mut output)
+        .deserialize(&mut deserializer)
+        .ok()?;
+
+    Some(output)
+}
+
+/// Transcode a JSON payload to ETF.
+#[cfg(not(feature = "simd-json"))]
+pub fn json_to_etf(json: &[u8]) -> Option<Vec<u8>> {
+    let mut deserializer = serde_json::Deserializer::from_slice(json);
+
+    let mut output = Vec::with_capacity(json.len());
+    output.push(FORMAT_VERSION);
+    Transcoder::new(&mut output)
+        .deserialize(&mut deserializer)
+        .ok()?;
+    deserializer.end().ok()?;
+
+    Some(output)
+}
+
+/// Reads ETF terms and writes them as JSON.
+struct Reader<'a> {
+    input: &'a [u8],
+    output: String,
+}
+
+impl<'a> Reader<'a> {
+    const fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.input.len() < len {
+            return None;
+        }
+
+        let (taken, rest) = self.input.split_at(len);
+        self.input = rest;
+
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?).into())
+    }
+
+    fn u32(&mut self) -> Option<usize> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?) as usize)
+    }
+
+    fn write_str(&mut self, value: &str) {
+        self.output.push('"');
+
+        for c in value.chars() {
+            match c {
+                '"' => self.output.push_str("\\\""),
+                '\\' => self.output.push_str("\\\\"),
+                '\n' => self.output.push_str("\\n"),
+                '\r' => self.output.push_str("\\r"),
+                '\t' => self.output.push_str("\\t"),
+                c if c.is_control() => {
+                    let _ = write!(self.output, "\\u{:04x}", c as u32);
+                }
+                c => self.output.push(c),
+            }
+        }
+
+        self.output.push('"');
+    }
+
+    fn atom(&mut self, len: usize) -> Option<()> {
+        let atom = std::str::from_utf8(self.take(len)?).ok()?;
+
+        match atom {
+            "nil" | "null" => self.output.push_str("null"),
+            "true" | "false" => self.output.push_str(atom),
+            _ => self.write_str(atom),
+        }
+
+        Some(())
+    }
+
+    fn big(&mut self, len: usize) -> Option<()> {
+        let negative = self.u8()? != 0;
+        let digits = self.take(len)?;
+
+        if len > 8 {
+            return None;
+        }
+
+        let mut bytes = [0; 8];
+        bytes[..len].copy_from_slice(digits);
+        let value = u64::from_le_bytes(bytes);
+
+        if negative {
+            let _ = write!(self.output, "-{value}");
+        } else {
+            let _ = write!(self.output, "{value}");
+        }
+
+        Some(())
+    }
+
+    fn elements(&mut self, len: usize, depth: usize) -> Option<()> {
+        self.output.push('[');
+
+        for i in 0..len {
+            if i > 0 {
+                self.output.push(',');
+            }
+
+            self.term(depth + 1)?;
+        }
+
+        self.output.push(']');
+
+        Some(())
+    }
+
+    fn key(&mut self) -> Option<()> {
+        // JSON only allows string keys, so stringify anything else
+        match self.u8()? {
+            tag
+            @ (ATOM_EXT | ATOM_UTF8_EXT | SMALL_ATOM_EXT | SMALL_ATOM_UTF8_EXT | BINARY_EXT) => {
+                let len = match tag {
+                    SMALL_ATOM_EXT | SMALL_ATOM_UTF8_EXT => self.u8()?.into(),
+                    BINARY_EXT => self.u32()?,
+                    _ => self.u16()?,
+                };
+                let key = std::str::from_utf8(self.take(len)?).ok()?;
+                self.write_str(key);
+            }
+            SMALL_INTEGER_EXT => {
+                let value = self.u8()?;
+                let _ = write!(self.output, "\"{value}\"");
+            }
+            INTEGER_EXT => {
+                let value = i32::from_be_bytes(self.take(4)?.try_into().ok()?);
+                let _ = write!(self.output, "\"{value}\"");
+            }
+            _ => return None,
+        }
+
+        Some(())
+    }
+
+    fn term(&mut self, depth: usize) -> Option<()> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        match self.u8()? {
+            NEW_FLOAT_EXT => {
+                let value = f64::from_be_bytes(self.take(8)?.try_into().ok()?);
+
+                if !value.is_finite() {
+                    return None;
+                }
+
+                let _ = write!(self.output, "{value}");
+            }
+            SMALL_INTEGER_EXT => {
+                let value = self.u8()?;
+                let _ = write!(self.output, "{value}");
+            }
+            INTEGER_EXT => {
+                let value = i32::from_be_bytes(self.take(4)?.try_into().ok()?);
+                let _ = write!(self.output, "{value}");
+            }
+            SMALL_BIG_EXT => {
+                let len = self.u8()?.into();
+                self.big(len)?;
+            }
+            LARGE_BIG_EXT => {
+                let len = self.u32()?;
+                self.big(len)?;
+            }
+            ATOM_EXT | ATOM_UTF8_EXT => {
+                let len = self.u16()?;
+                self.atom(len)?;
+            }
+            SMALL_ATOM_EXT | SMALL_ATOM_UTF8_EXT => {
+                let len = self.u8()?.into();
+                self.atom(len)?;
+            }
+            BINARY_EXT => {
+                let len = self.u32()?;
+                let value = std::str::from_utf8(self.take(len)?).ok()?;
+                self.write_str(value);
+            }
+            STRING_EXT => {
+                // A list of small integers
+                let len = self.u16()?;
+                let bytes = self.take(len)?;
+                self.output.push('[');
+
+                for (i, byte) in bytes.iter().enumerate() {
+                    if i > 0 {
+                        self.output.push(',');
+                    }
+
+                    let _ = write!(self.output, "{byte}");
+                }
+
+                self.output.push(']');
+            }
+            NIL_EXT => self.output.push_str("[]"),
+            LIST_EXT => {
+                let len = self.u32()?;
+                self.elements(len, depth)?;
+
+                // Only proper lists can be represented in JSON
+                if self.u8()? != NIL_EXT {
+                    return None;
+                }
+            }
+            SMALL_TUPLE_EXT => {
+                let len = self.u8()?.into();
+                self.elements(len, depth)?;
+            }
+            LARGE_TUPLE_EXT => {
+                let len = self.u32()?;
+                self.elements(len, depth)?;
+            }
+            MAP_EXT => {
+                let arity = self.u32()?;
+                self.output.push('{');
+
+                for i in 0..arity {
+                    if i > 0 {
+                        self.output.push(',');
+                    }
+
+                    self.key()?;
+                    self.output.push(':');
+                    self.term(depth + 1)?;
+                }
+
+                self.output.push('}');
+            }
+            _ => return None,
+        }
+
+        Some(())
+    }
+}
+
+/// Transcode an ETF payload to JSON.
+pub fn etf_to_json(etf: &[u8]) -> Option<String> {
+    let mut reader = Reader {
+        input: etf,
+        output: String::with_capacity(etf.len() * 2),
+    };
+
+    if reader.u8()? != FORMAT_VERSION {
+        return None;
+    }
+
+    reader.term(0)?;
+
+    reader.input.is_empty().then_some(reader.output)
+}
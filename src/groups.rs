@@ -0,0 +1,210 @@
+use tracing::debug;
+
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    ops::Range,
+    sync::RwLock,
+};
+
+use crate::state::Session;
+
+/// Worker groups of a shard, between whose connected members the events of
+/// the shard are partitioned.
+pub struct Groups {
+    /// IDs of the connected sessions in each group.
+    members: RwLock<HashMap<String, Vec<String>>>,
+}
+
+impl Groups {
+    pub fn new() -> Self {
+        Self {
+            members: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Add a session to its group, if it has one.
+    pub fn join(&self, shard_id: u32, session: &Session) {
+        let Some(group) = &session.group else {
+            return;
+        };
+
+        let count = {
+            let mut groups = self.members.write().unwrap();
+            let members = groups.entry(group.clone()).or_default();
+
+            if !members.contains(&session.id) {
+                members.push(session.id.clone());
+            }
+
+            let count = members.len();
+            drop(groups);
+
+            count
+        };
+
+        debug!("[Shard {shard_id}] Worker group {group} now has {count} members");
+    }
+
+    /// Remove a session from its group, if it has one.
+    pub fn leave(&self, shard_id: u32, session: &Session) {
+        let Some(group) = &session.group else {
+            return;
+        };
+
+        let count = {
+            let mut groups = self.members.write().unwrap();
+            let Some(members) = groups.get_mut(group) else {
+                return;
+            };
+
+            members.retain(|id| *id != session.id);
+            let count = members.len();
+
+            if count == 0 {
+                groups.remove(group);
+            }
+
+            count
+        };
+
+        debug!("[Shard {shard_id}] Worker group {group} now has {count} members");
+    }
+
+    /// Get the IDs of the sessions that receive an event with the given
+    /// partition key, one for each group.
+    ///
+    /// Members are chosen by rendezvous hashing, so that only the keys of a
+    /// member that joins or leaves move to a different member.
+    pub fn owners(&self, key: u64) -> Vec<String> {
+        self.members
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|members| {
+                members
+                    .iter()
+                    .max_by_key(|id| {
+                        let mut hasher = DefaultHasher::new();
+                        key.hash(&mut hasher);
+                        id.hash(&mut hasher);
+                        hasher.finish()
+                    })
+                    .cloned()
+            })
+            .collect()
+    }
+}
+
+/// Get the ID of the guild that a dispatch belongs to, which is used to
+/// partition events between the members of worker groups.
+///
+/// The payload is scanned for the `guild_id` key (or `id` for events about a
+/// guild itself) directly inside of the `d` object, without deserializing it.
+pub fn guild_id(event_name: &str, payload: &str) -> Option<u64> {
+    let key = match event_name {
+        "GUILD_CREATE" | "GUILD_UPDATE" | "GUILD_DELETE" => "id",
+        _ => "guild_id",
+    };
+
+    find_integer(payload, key)
+}
+
+/// Get an integer, or a snowflake in a string, directly inside of the `d`
+/// object of a payload.
+pub fn find_integer(payload: &str, key: &str) -> Option<u64> {
+    let value = &payload[find_value(payload, key)?..];
+    let value = value.strip_prefix('"').unwrap_or(value);
+    let len = value.find(|c: char| !c.is_ascii_digit())?;
+
+    value[..len].parse().ok()
+}
+
+/// Get the position of a string directly inside of the `d` object of a
+/// payload, without its quotes.
+pub fn find_string(payload: &str, key: &str) -> Option<Range<usize>> {
+    let start = find_value(payload, key)?;
+
+    if payload.as_bytes().get(start) != Some(&b'"') {
+        return None;
+    }
+
+    let end = string_end(payload.as_bytes(), start + 1)?;
+
+    Some(start + 1..end)
+}
+
+/// Find where the value of a key directly inside of the `d` object starts.
+fn find_value(payload: &str, key: &str) -> Option<usize> {
+    let bytes = payload.as_bytes();
+    let mut depth = 0_usize;
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'"' => {
+                let start = idx + 1;
+                let end = string_end(bytes, start)?;
+                idx = end + 1;
+
+                // Keys of the `d` object are at depth 2
+                if depth != 2 || &payload[start..end] != key {
+                    continue;
+                }
+
+                // A string value that equals the key is not followed by a colon
+                let Some(rest) = payload[idx..].trim_start().strip_prefix(':') else {
+                    continue;
+                };
+                let value = rest.trim_start();
+
+                return Some(payload.len() - value.len());
+            }
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+
+        idx += 1;
+    }
+
+    None
+}
+
+/// Find the closing quote of a JSON string starting at `start`.
+fn string_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut idx = start;
+
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'\\' => idx += 2,
+            b'"' => return Some(idx),
+            _ => idx += 1,
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::guild_id;
+
+    #[test]
+    fn value_equal_to_key() {
+        let payload = r#"{"t":"MESSAGE_CREATE","s":3,"op":0,"d":{"content":"guild_id","guild_id":"81384788765712384"}}"#;
+
+        assert_eq!(
+            guild_id("MESSAGE_CREATE", payload),
+            Some(81_384_788_765_712_384)
+        );
+    }
+
+    #[test]
+    fn nested_key() {
+        let payload =
+            r#"{"t":"MESSAGE_CREATE","s":3,"op":0,"d":{"author":{"guild_id":"1"},"content":"hi"}}"#;
+
+        assert_eq!(guild_id("MESSAGE_CREATE", payload), None);
+    }
+}
@@ -0,0 +1,101 @@
+use twilight_gateway::Intents;
+
+use crate::groups;
+
+/// Get the intents of which a client needs at least one to receive an event.
+///
+/// Events that are sent regardless of intents return empty intents.
+pub fn required(event_name: &str, payload: &str) -> Intents {
+    // Some events are sent for guilds and DMs under different intents
+    let in_guild = || groups::find_integer(payload, "guild_id").is_some();
+
+    match event_name {
+        "GUILD_CREATE"
+        | "GUILD_UPDATE"
+        | "GUILD_DELETE"
+        | "GUILD_ROLE_CREATE"
+        | "GUILD_ROLE_UPDATE"
+        | "GUILD_ROLE_DELETE"
+        | "CHANNEL_CREATE"
+        | "CHANNEL_UPDATE"
+        | "CHANNEL_DELETE"
+        | "THREAD_CREATE"
+        | "THREAD_UPDATE"
+        | "THREAD_DELETE"
+        | "THREAD_LIST_SYNC"
+        | "THREAD_MEMBER_UPDATE"
+        | "STAGE_INSTANCE_CREATE"
+        | "STAGE_INSTANCE_UPDATE"
+        | "STAGE_INSTANCE_DELETE" => Intents::GUILDS,
+        "CHANNEL_PINS_UPDATE" => {
+            if in_guild() {
+                Intents::GUILDS
+            } else {
+                Intents::DIRECT_MESSAGES
+            }
+        }
+        "THREAD_MEMBERS_UPDATE"
+        | "GUILD_MEMBER_ADD"
+        | "GUILD_MEMBER_UPDATE"
+        | "GUILD_MEMBER_REMOVE" => Intents::GUILD_MEMBERS,
+        "GUILD_AUDIT_LOG_ENTRY_CREATE" | "GUILD_BAN_ADD" | "GUILD_BAN_REMOVE" => {
+            Intents::GUILD_MODERATION
+        }
+        "GUILD_EMOJIS_UPDATE"
+        | "GUILD_STICKERS_UPDATE"
+        | "GUILD_SOUNDBOARD_SOUND_CREATE"
+        | "GUILD_SOUNDBOARD_SOUND_UPDATE"
+        | "GUILD_SOUNDBOARD_SOUND_DELETE"
+        | "GUILD_SOUNDBOARD_SOUNDS_UPDATE" => Intents::GUILD_EMOJIS_AND_STICKERS,
+        "GUILD_INTEGRATIONS_UPDATE"
+        | "INTEGRATION_CREATE"
+        | "INTEGRATION_UPDATE"
+        | "INTEGRATION_DELETE" => Intents::GUILD_INTEGRATIONS,
+        "WEBHOOKS_UPDATE" => Intents::GUILD_WEBHOOKS,
+        "INVITE_CREATE" | "INVITE_DELETE" => Intents::GUILD_INVITES,
+        "VOICE_CHANNEL_EFFECT_SEND" | "VOICE_STATE_UPDATE" => Intents::GUILD_VOICE_STATES,
+        "PRESENCE_UPDATE" => Intents::GUILD_PRESENCES,
+        "MESSAGE_CREATE" | "MESSAGE_UPDATE" | "MESSAGE_DELETE" => {
+            if in_guild() {
+                Intents::GUILD_MESSAGES
+            } else {
+                Intents::DIRECT_MESSAGES
+            }
+        }
+        "MESSAGE_DELETE_BULK" => Intents::GUILD_MESSAGES,
+        "MESSAGE_REACTION_ADD"
+        | "MESSAGE_REACTION_REMOVE"
+        | "MESSAGE_REACTION_REMOVE_ALL"
+        | "MESSAGE_REACTION_REMOVE_EMOJI" => {
+            if in_guild() {
+                Intents::GUILD_MESSAGE_REACTIONS
+            } else {
+                Intents::DIRECT_MESSAGE_REACTIONS
+            }
+        }
+        "TYPING_START" => {
+            if in_guild() {
+                Intents::GUILD_MESSAGE_TYPING
+            } else {
+                Intents::DIRECT_MESSAGE_TYPING
+            }
+        }
+        "GUILD_SCHEDULED_EVENT_CREATE"
+        | "GUILD_SCHEDULED_EVENT_UPDATE"
+        | "GUILD_SCHEDULED_EVENT_DELETE"
+        | "GUILD_SCHEDULED_EVENT_USER_ADD"
+        | "GUILD_SCHEDULED_EVENT_USER_REMOVE" => Intents::GUILD_SCHEDULED_EVENTS,
+        "AUTO_MODERATION_RULE_CREATE"
+        | "AUTO_MODERATION_RULE_UPDATE"
+        | "AUTO_MODERATION_RULE_DELETE" => Intents::AUTO_MODERATION_CONFIGURATION,
+        "AUTO_MODERATION_ACTION_EXECUTION" => Intents::AUTO_MODERATION_EXECUTION,
+        "MESSAGE_POLL_VOTE_ADD" | "MESSAGE_POLL_VOTE_REMOVE" => {
+            if in_guild() {
+                Intents::GUILD_MESSAGE_POLLS
+            } else {
+                Intents::DIRECT_MESSAGE_POLLS
+            }
+        }
+        _ => Intents::empty(),
+    }
+}
@@ -11,41 +11,53 @@ use metrics_exporter_prometheus::PrometheusBuilder;
 use mimalloc::MiMalloc;
 use tokio::{
     signal::unix::{signal, SignalKind},
-    sync::broadcast,
-    task::JoinSet,
     time::timeout,
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{
     filter::LevelFilter, layer::SubscriberExt, reload, util::SubscriberInitExt,
 };
-use twilight_cache_inmemory::InMemoryCache;
-use twilight_gateway::{CloseFrame, ConfigBuilder, Shard, ShardId};
+use twilight_gateway::{CloseFrame, ConfigBuilder};
 use twilight_gateway_queue::InMemoryQueue;
 use twilight_http::Client;
-use twilight_model::gateway::payload::outgoing::update_presence::UpdatePresencePayload;
 
 use std::{
     collections::HashMap,
     error::Error,
+    net::SocketAddr,
     str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, RwLock,
+        Arc,
     },
     time::Duration,
 };
 
 use crate::config::CONFIG;
 
+mod admin;
+mod api;
+mod auth;
 mod cache;
+mod cluster;
+mod compression;
 mod config;
 mod deserializer;
 mod dispatch;
+mod encoding;
+mod groups;
+mod intents;
 mod model;
+mod persistence;
+mod presence;
+mod queue;
+mod ratelimit;
+mod responses;
 mod server;
 mod state;
+mod tls;
 mod upgrade;
+mod voice;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -66,8 +78,6 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
         .with(reload_level_filter)
         .init();
 
-    tokio::spawn(config::watch_config_changes(reload_handle));
-
     // Set up metrics collection
     let metrics_handle = PrometheusBuilder::new().install_recorder().unwrap();
 
@@ -99,7 +109,6 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
     let shard_start = CONFIG.shard_start.unwrap_or(0);
     let shard_end = CONFIG.shard_end.unwrap_or(shard_count);
     let shard_end_inclusive = shard_end - 1;
-    let mut shards = Vec::with_capacity((shard_end - shard_start) as usize);
 
     info!("Creating shards {shard_start} to {shard_end_inclusive} of {shard_count} total",);
 
@@ -107,68 +116,66 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
         .queue(queue)
         .build();
 
-    let mut dispatch_tasks = JoinSet::new();
+    // Sessions saved on the last shutdown are resumed instead of identifying again
+    let mut saved_shards = CONFIG
+        .state_file
+        .as_deref()
+        .map_or_else(HashMap::new, |path| persistence::load(path, shard_count));
 
-    for shard_id in shard_start..shard_end {
-        let mut builder = ConfigBuilder::from(config.clone());
+    let inner = cluster::start(
+        &config,
+        shard_count,
+        shard_start..shard_end,
+        &mut saved_shards,
+    );
 
-        if let Some(mut activity) = CONFIG.activity.clone() {
-            // Replace {{shard}} with the actual ID
-            activity.name = activity.name.replace("{{shard}}", &shard_id.to_string());
-            // Will only error if activities are empty, so we can unwrap
-            builder = builder.presence(
-                UpdatePresencePayload::new(vec![activity], false, None, CONFIG.status).unwrap(),
-            );
+    let state = Arc::new(state::Current::new(inner));
+
+    // The shard count can only change if the proxy runs all shards
+    if let Some(reshard_interval) = CONFIG.reshard_interval() {
+        if CONFIG.shards.is_none() && CONFIG.shard_start.is_none() && CONFIG.shard_end.is_none() {
+            tokio::spawn(cluster::reshard(
+                state.clone(),
+                client.clone(),
+                config,
+                reshard_interval,
+            ));
+        } else {
+            warn!("Automatic resharding is disabled because the shards are configured manually");
         }
+    }
 
-        let shard = Shard::with_config(ShardId::new(shard_id, shard_count), builder.build());
-
-        // To support multiple listeners on the same shard
-        // we need to make a broadcast channel with the events
-        let (broadcast_tx, _) = broadcast::channel(CONFIG.backpressure);
-
-        let cache = Arc::new(
-            InMemoryCache::builder()
-                .resource_types(CONFIG.cache.clone().into())
-                .message_cache_size(0)
-                .build(),
-        );
-        let guild_cache = cache::Guilds::new(cache.clone());
-
-        let ready = state::Ready::new();
-
-        let shard_status = Arc::new(state::Shard {
-            id: shard_id,
-            sender: shard.sender(),
-            events: broadcast_tx.clone(),
-            ready,
-            guilds: guild_cache,
-        });
-
-        // Now pipe the events into the broadcast
-        // and handle state updates for the guild cache
-        // and set the ready event if received
-        dispatch_tasks.spawn(dispatch::events(
-            shard,
-            shard_status.clone(),
-            shard_id,
-            broadcast_tx,
-        ));
+    tokio::spawn(state::collect_sessions(state.clone()));
 
-        shards.push(shard_status);
+    tokio::spawn(config::watch_config_changes(reload_handle, state.clone()));
 
-        debug!("Created shard {shard_id} of {shard_count} total");
+    if let Some(admin) = &CONFIG.admin {
+        tokio::spawn(admin::run(
+            SocketAddr::new(admin.address, admin.port),
+            state.clone(),
+        ));
     }
 
-    let state = Arc::new(state::Inner {
-        shards,
-        shard_count,
-        sessions: RwLock::new(HashMap::new()),
-    });
+    let tls = match CONFIG.tls.clone() {
+        Some(tls) => {
+            let acceptor = Arc::new(tls::Acceptor::new(tls)?);
+            tokio::spawn(tls::watch_certificate(acceptor.clone()));
+            Some(acceptor)
+        }
+        None => None,
+    };
+
+    if let Some(unix_socket) = &CONFIG.unix_socket {
+        tokio::spawn(server::run_unix(
+            unix_socket,
+            state.clone(),
+            metrics_handle.clone(),
+        ));
+    }
 
     let state_clone = state.clone();
     tokio::spawn(async move {
-        if let Err(e) = server::run(CONFIG.port, state_clone, metrics_handle).await {
+        if let Err(e) = server::run(CONFIG.port, state_clone, metrics_handle, tls).await {
             error!("{}", e);
         }
     });
@@ -184,11 +191,23 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
     // Set the flag so that event handlers will be able to tell that a GatewayClose is an expected shutdown
     SHUTDOWN.store(true, Ordering::Relaxed);
 
+    // Keep the sessions alive on Discord's side if they are saved for resuming
+    let close_frame = if CONFIG.state_file.is_some() {
+        CloseFrame::RESUME
+    } else {
+        CloseFrame::NORMAL
+    };
+
+    let inner = state.get();
+
     // Initiate the shutdown for all shards
-    for shard in &state.shards {
-        let _ = shard.sender.close(CloseFrame::NORMAL);
+    for shard in &inner.shards {
+        let _ = shard.sender.close(close_frame.clone());
     }
 
+    let mut dispatch_tasks = std::mem::take(&mut *inner.dispatch_tasks.lock().unwrap());
+    let mut saved_shards = Vec::with_capacity(dispatch_tasks.len());
+
     let mut graceful = 0;
     let mut ungraceful = dispatch_tasks.len();
 
@@ -197,8 +216,13 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     loop {
         match timeout(Duration::from_secs(10), dispatch_tasks.join_next()).await {
-            Ok(Some(_)) => {
+            Ok(Some(result)) => {
                 debug!("shard dispatching task shut down");
+
+                if let Ok(Some(saved_shard)) = result {
+                    saved_shards.push(saved_shard);
+                }
+
                 graceful += 1;
                 ungraceful -= 1;
             } // Shard task shut down
@@ -212,6 +236,10 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     info!("{graceful} shards shut down gracefully, {ungraceful} not gracefully");
 
+    if let Some(path) = &CONFIG.state_file {
+        persistence::save(path, inner.shard_count, saved_shards);
+    }
+
     Ok(())
 }
 
@@ -1,8 +1,13 @@
-use serde::Deserialize;
+use serde::{de::IgnoredAny, Deserialize};
 #[cfg(not(feature = "simd-json"))]
 use serde_json::Value as OwnedValue;
 #[cfg(feature = "simd-json")]
 use simd_json::OwnedValue;
+use twilight_gateway::Intents;
+use twilight_model::{
+    gateway::payload::outgoing::update_presence::UpdatePresencePayload,
+    id::{marker::GuildMarker, Id},
+};
 
 #[derive(Deserialize)]
 pub struct Identify {
@@ -18,10 +23,32 @@ pub struct Resume {
 pub struct IdentifyInfo {
     #[serde(default)]
     pub compress: Option<bool>,
-    pub shard: [u32; 2],
+    #[serde(default)]
+    pub intents: Option<Intents>,
+    #[serde(default)]
+    pub presence: Option<IdentifyPresence>,
+    #[serde(default)]
+    pub properties: Option<IdentifyProperties>,
+    #[serde(default)]
+    pub shard: Option<[u32; 2]>,
     pub token: String,
 }
 
+/// Presence sent in IDENTIFY, which is ignored instead of failing the IDENTIFY
+/// if it is invalid.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum IdentifyPresence {
+    Valid(UpdatePresencePayload),
+    Invalid(IgnoredAny),
+}
+
+#[derive(Deserialize)]
+pub struct IdentifyProperties {
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub struct ResumeInfo {
     pub session_id: String,
@@ -29,6 +56,16 @@ pub struct ResumeInfo {
     pub token: String,
 }
 
+#[derive(Deserialize)]
+pub struct RequestSoundboardSounds {
+    pub d: RequestSoundboardSoundsInfo,
+}
+
+#[derive(Deserialize)]
+pub struct RequestSoundboardSoundsInfo {
+    pub guild_ids: Vec<Id<GuildMarker>>,
+}
+
 #[derive(Deserialize)]
 pub struct Ready {
     pub d: JsonObject,
@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+#[cfg(not(feature = "simd-json"))]
+use serde_json::to_string;
+#[cfg(feature = "simd-json")]
+use simd_json::to_string;
+use tracing::{debug, info, warn};
+use twilight_gateway::{parse, Event, EventTypeFlags, Intents, Session};
+use twilight_model::gateway::event::GatewayEvent as TwilightGatewayEvent;
+
+use std::{
+    collections::HashMap,
+    fs::{read_to_string, remove_file, rename, OpenOptions},
+    io::{Result as IoResult, Write},
+    iter::once,
+    os::unix::fs::OpenOptionsExt,
+};
+
+use crate::{config::CONFIG, model::JsonObject, state::Shard};
+
+/// Upstream session of a shard that is saved on shutdown, so that the shard
+/// can resume instead of identify on the next start.
+#[derive(Deserialize, Serialize)]
+pub struct SavedShard {
+    /// ID of the shard.
+    pub id: u32,
+    /// Discord session of the shard.
+    pub session: Session,
+    /// URL that the shard has to resume with.
+    pub resume_url: Option<String>,
+    /// READY payload that clients receive.
+    ready: JsonObject,
+    /// Cached guilds as `GUILD_CREATE` and `GUILD_DELETE` payloads.
+    guilds: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SavedState {
+    shard_count: u32,
+    shards: Vec<SavedShard>,
+}
+
+impl SavedShard {
+    /// Save the session of a shard together with its READY and cache state.
+    ///
+    /// Returns [`None`] if the shard is not READY.
+    pub fn new(shard: &Shard, session: Session, resume_url: Option<String>) -> Option<Self> {
+        let ready = shard.ready.get()?;
+
+        // The sequence numbers of these payloads are irrelevant
+        let guilds = shard
+            .guilds
+            .get_guild_payloads(Intents::all(), &mut 0)
+            .collect();
+
+        Some(Self {
+            id: shard.id,
+            session,
+            resume_url,
+            ready,
+            guilds,
+        })
+    }
+
+    /// Restore the READY and cache state of a shard.
+    pub fn restore(self, shard: &Shard) {
+        let event_type_flags: EventTypeFlags = CONFIG.cache.clone().into();
+
+        // The cache needs the READY for the current user
+        let ready = to_string(&self.ready).map_or_else(
+            |_| String::new(),
+            |ready| format!(r#"{{"t":"READY","s":0,"op":0,"d":{ready}}}"#),
+        );
+
+        for payload in once(ready).chain(self.guilds) {
+            if let Ok(Some(TwilightGatewayEvent::Dispatch(_, event))) =
+                parse(payload, event_type_flags)
+            {
+                shard.guilds.update(Event::from(event));
+            }
+        }
+
+        shard.ready.set_ready(self.ready);
+    }
+}
+
+/// Load the saved shards from the state file, if there is one and it was
+/// written for the same shard count.
+#[cfg(feature = "simd-json")]
+pub fn load(path: &str, shard_count: u32) -> HashMap<u32, SavedShard> {
+    let Ok(mut content) = read_to_string(path) else {
+        return HashMap::new();
+    };
+
+    match unsafe { simd_json::from_str::<SavedState>(&mut content) } {
+        Ok(state) => from_state(state, shard_count),
+        Err(e) => {
+            warn!("Failed to load state file {path}: {e}");
+            HashMap::new()
+        }
+    }
+}
+
+/// Load the saved shards from the state file, if there is one and it was
+/// written for the same shard count.
+#[cfg(not(feature = "simd-json"))]
+pub fn load(path: &str, shard_count: u32) -> HashMap<u32, SavedShard> {
+    let Ok(content) = read_to_string(path) else {
+        return HashMap::new();
+    };
+
+    match serde_json::from_str::<SavedState>(&content) {
+        Ok(state) => from_state(state, shard_count),
+        Err(e) => {
+            warn!("Failed to load state file {path}: {e}");
+            HashMap::new()
+        }
+    }
+}
+
+fn from_state(state: SavedState, shard_count: u32) -> HashMap<u32, SavedShard> {
+    if state.shard_count != shard_count {
+        info!(
+            "State file is for {} shards instead of {shard_count}, identifying all shards",
+            state.shard_count
+        );
+        return HashMap::new();
+    }
+
+    debug!("Loaded {} saved shard sessions", state.shards.len());
+
+    state
+        .shards
+        .into_iter()
+        .map(|shard| (shard.id, shard))
+        .collect()
+}
+
+/// Write a file that only the owner can read, since it contains the bot's
+/// sessions.
+fn write_private(path: &str, content: &str) -> IoResult<()> {
+    // The mode only applies to new files
+    let _res = remove_file(path);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+
+    file.write_all(content.as_bytes())?;
+    file.sync_all()
+}
+
+/// Write the saved shards to the state file.
+pub fn save(path: &str, shard_count: u32, shards: Vec<SavedShard>) {
+    let count = shards.len();
+    let state = SavedState {
+        shard_count,
+        shards,
+    };
+
+    let Ok(content) = to_string(&state) else {
+        warn!("Failed to serialize shard sessions");
+        return;
+    };
+
+    // Write to a temporary file first so that an interrupted write does not
+    // leave a corrupt state file behind
+    let temp_path = format!("{path}.tmp");
+
+    if let Err(e) = write_private(&temp_path, &content).and_then(|()| rename(&temp_path, path)) {
+        warn!("Failed to write state file {path}: {e}");
+    } else {
+        info!("Saved {count} shard sessions to {path}");
+    }
+}
@@ -0,0 +1,80 @@
+use twilight_model::gateway::payload::outgoing::update_presence::UpdatePresencePayload;
+
+use std::sync::Mutex;
+
+use crate::config::PresencePolicy;
+
+/// A presence that a client set.
+struct Update {
+    payload: UpdatePresencePayload,
+    session_id: String,
+}
+
+/// Presence of a shard as set by its clients, which the proxy applies again
+/// whenever the shard identifies.
+pub struct Presence {
+    inner: Mutex<Option<Update>>,
+}
+
+impl Presence {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(None),
+        }
+    }
+
+    /// Get the presence that a client set, unless the configured presence
+    /// applies.
+    pub fn get(&self) -> Option<UpdatePresencePayload> {
+        self.inner
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|update| update.payload.clone())
+    }
+
+    /// Let a session set the presence if the policy allows it.
+    ///
+    /// `is_active` tells whether the session that set the current presence
+    /// still exists. Returns false if the presence must not be sent to
+    /// Discord, because it was rejected or did not change.
+    pub fn update(
+        &self,
+        session_id: &str,
+        payload: UpdatePresencePayload,
+        policy: PresencePolicy,
+        is_active: impl FnOnce(&str) -> bool,
+    ) -> bool {
+        let mut current = self.inner.lock().unwrap();
+
+        let allowed = match policy {
+            PresencePolicy::Latest => true,
+            PresencePolicy::First => current.as_ref().is_none_or(|update| {
+                update.session_id == session_id || !is_active(&update.session_id)
+            }),
+            PresencePolicy::Config => false,
+        };
+
+        if !allowed {
+            return false;
+        }
+
+        let changed = current
+            .as_ref()
+            .is_none_or(|update| update.payload != payload);
+
+        *current = Some(Update {
+            payload,
+            session_id: session_id.to_owned(),
+        });
+
+        drop(current);
+
+        changed
+    }
+
+    /// Forget the presence set by clients, so that the configured one applies.
+    pub fn reset(&self) {
+        *self.inner.lock().unwrap() = None;
+    }
+}
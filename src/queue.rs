@@ -0,0 +1,193 @@
+use metrics::Gauge;
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    Semaphore,
+};
+use tokio_websockets::Message;
+
+use std::sync::{Arc, OnceLock, Weak};
+
+use crate::config::{ClientQueue, QueuePolicy};
+
+/// Error returned when a message does not fit into the queue of a client.
+pub struct QueueFull;
+
+struct Queued {
+    message: Message,
+    /// Bytes that the message takes up from the budget, if it is counted.
+    bytes: Option<u32>,
+}
+
+struct Budget {
+    messages: Semaphore,
+    bytes: Semaphore,
+    max_messages: usize,
+    max_bytes: usize,
+    policy: QueuePolicy,
+    /// Queued messages and bytes of all clients of a shard, which this queue
+    /// adds to once it belongs to a session.
+    gauges: OnceLock<(Gauge, Gauge)>,
+}
+
+impl Budget {
+    /// Messages and bytes that are currently queued.
+    fn depth(&self) -> (usize, usize) {
+        (
+            self.max_messages - self.messages.available_permits(),
+            self.max_bytes - self.bytes.available_permits(),
+        )
+    }
+
+    /// Add a change in the depth of the queue to the metrics of its shard.
+    fn update_metrics(&self, messages: f64, bytes: f64) {
+        if let Some((message_gauge, byte_gauge)) = self.gauges.get() {
+            message_gauge.increment(messages);
+            byte_gauge.increment(bytes);
+        }
+    }
+}
+
+impl Drop for Budget {
+    fn drop(&mut self) {
+        // Messages that were never written no longer count
+        let (messages, bytes) = self.depth();
+
+        self.update_metrics(-(messages as f64), -(bytes as f64));
+    }
+}
+
+/// Handle to the depth of the queue of a client that does not keep the queue
+/// alive.
+pub struct Depth(Weak<Budget>);
+
+impl Depth {
+    /// Messages and bytes in the queue, or [`None`] if the client is gone.
+    pub fn get(&self) -> Option<(usize, usize)> {
+        self.0.upgrade().map(|budget| budget.depth())
+    }
+}
+
+/// Sending half of the queue of messages for a client.
+#[derive(Clone)]
+pub struct Sender {
+    tx: UnboundedSender<Queued>,
+    budget: Arc<Budget>,
+}
+
+/// Receiving half of the queue of messages for a client.
+pub struct Receiver {
+    rx: UnboundedReceiver<Queued>,
+    budget: Arc<Budget>,
+}
+
+/// Create a queue of messages for a client that is limited by a number of
+/// messages and bytes.
+pub fn channel(config: &ClientQueue) -> (Sender, Receiver) {
+    let (tx, rx) = unbounded_channel();
+
+    // Semaphores can not hold more permits than this
+    let max_messages = config.messages.clamp(1, Semaphore::MAX_PERMITS);
+    let max_bytes = config.bytes.clamp(1, Semaphore::MAX_PERMITS);
+
+    let budget = Arc::new(Budget {
+        messages: Semaphore::new(max_messages),
+        bytes: Semaphore::new(max_bytes),
+        max_messages,
+        max_bytes,
+        policy: config.policy,
+        gauges: OnceLock::new(),
+    });
+
+    (
+        Sender {
+            tx,
+            budget: budget.clone(),
+        },
+        Receiver { rx, budget },
+    )
+}
+
+impl Sender {
+    /// Queue a message that does not count towards the budget, such as a
+    /// heartbeat ACK or close frame.
+    pub fn send_control(&self, message: Message) {
+        let _res = self.tx.send(Queued {
+            message,
+            bytes: None,
+        });
+    }
+
+    /// Queue an event.
+    ///
+    /// If the budget is used up, this waits until the client has caught up or
+    /// fails, depending on the configured policy.
+    pub async fn send(&self, message: Message) -> Result<(), QueueFull> {
+        let budget = &self.budget;
+
+        // Messages larger than the whole budget have to fit in as well, and
+        // semaphores hand out at most u32::MAX permits at once
+        let bytes =
+            u32::try_from(message.as_payload().len().min(budget.max_bytes)).unwrap_or(u32::MAX);
+
+        let permits = match budget.policy {
+            QueuePolicy::Wait => budget
+                .messages
+                .acquire()
+                .await
+                .ok()
+                .zip(budget.bytes.acquire_many(bytes).await.ok()),
+            QueuePolicy::Disconnect => budget
+                .messages
+                .try_acquire()
+                .ok()
+                .zip(budget.bytes.try_acquire_many(bytes).ok()),
+        };
+
+        let Some((message_permit, byte_permits)) = permits else {
+            return Err(QueueFull);
+        };
+
+        // The permits are given back by the receiver
+        message_permit.forget();
+        byte_permits.forget();
+
+        let _res = self.tx.send(Queued {
+            message,
+            bytes: Some(bytes),
+        });
+
+        budget.update_metrics(1.0, f64::from(bytes));
+
+        Ok(())
+    }
+
+    /// Handle to the depth of this queue.
+    pub fn depth(&self) -> Depth {
+        Depth(Arc::downgrade(&self.budget))
+    }
+
+    /// Report the depth of this queue in the metrics of a shard.
+    ///
+    /// This has to happen before any event is queued.
+    pub fn track(&self, shard_id: u32) {
+        let _res = self.budget.gauges.set((
+            metrics::gauge!("gateway_client_queue_messages", "shard" => shard_id.to_string()),
+            metrics::gauge!("gateway_client_queue_bytes", "shard" => shard_id.to_string()),
+        ));
+    }
+}
+
+impl Receiver {
+    /// Take the next message from the queue.
+    pub async fn recv(&mut self) -> Option<Message> {
+        let queued = self.rx.recv().await?;
+
+        if let Some(bytes) = queued.bytes {
+            self.budget.messages.add_permits(1);
+            self.budget.bytes.add_permits(bytes as usize);
+            self.budget.update_metrics(-1.0, -f64::from(bytes));
+        }
+
+        Some(queued.message)
+    }
+}
@@ -0,0 +1,154 @@
+#[cfg(not(feature = "simd-json"))]
+use serde_json::to_string;
+#[cfg(feature = "simd-json")]
+use simd_json::to_string;
+use tokio::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::{sleep, Instant},
+};
+use twilight_gateway::MessageSender;
+use twilight_model::gateway::{
+    payload::outgoing::{update_presence::UpdatePresencePayload, UpdatePresence},
+    OpCode,
+};
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::config::{self, CommandPolicy};
+
+/// Window in which Discord allows a shard to send at most 120 commands.
+const WINDOW: Duration = Duration::from_mins(1);
+
+/// Commands that clients and the proxy may send to a shard per window. The
+/// rest is left for heartbeats.
+const COMMANDS: usize = 110;
+
+/// Commands that can be queued for a shard before further ones are rejected.
+const QUEUE_SIZE: usize = 1000;
+
+/// Error returned when a command exceeds the rate limit of a shard.
+pub struct RateLimited;
+
+/// Sliding window rate limiter.
+struct Ratelimiter {
+    sent: Mutex<VecDeque<Instant>>,
+}
+
+impl Ratelimiter {
+    fn new() -> Self {
+        Self {
+            sent: Mutex::new(VecDeque::with_capacity(COMMANDS)),
+        }
+    }
+
+    /// Take a slot if one is free, otherwise return the time until the next
+    /// slot frees up.
+    fn poll(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let mut sent = self.sent.lock().unwrap();
+
+        while sent
+            .front()
+            .is_some_and(|sent_at| now.duration_since(*sent_at) >= WINDOW)
+        {
+            sent.pop_front();
+        }
+
+        let wait = if sent.len() < COMMANDS {
+            sent.push_back(now);
+            None
+        } else {
+            sent.front()
+                .map(|sent_at| WINDOW.saturating_sub(now.duration_since(*sent_at)))
+        };
+
+        drop(sent);
+
+        wait
+    }
+
+    fn try_acquire(&self) -> bool {
+        self.poll().is_none()
+    }
+
+    async fn acquire(&self) {
+        while let Some(wait) = self.poll() {
+            sleep(wait).await;
+        }
+    }
+}
+
+/// Commands that clients and the proxy send to a shard, rate limited across
+/// all of them so that they can not get the shard disconnected together.
+pub struct Commands {
+    ratelimiter: Arc<Ratelimiter>,
+    queue: Sender<String>,
+    sender: MessageSender,
+}
+
+impl Commands {
+    pub fn new(sender: MessageSender) -> Self {
+        let ratelimiter = Arc::new(Ratelimiter::new());
+        let (queue, rx) = channel(QUEUE_SIZE);
+
+        tokio::spawn(forward_commands(rx, ratelimiter.clone(), sender.clone()));
+
+        Self {
+            ratelimiter,
+            queue,
+            sender,
+        }
+    }
+
+    /// Send a command to the shard.
+    ///
+    /// Depending on the configured policy, a command that exceeds the rate
+    /// limit is either queued or rejected. Commands are also rejected when the
+    /// queue is full.
+    pub fn send(&self, command: String) -> Result<(), RateLimited> {
+        match config::current().command_policy {
+            CommandPolicy::Queue => self.queue.try_send(command).map_err(|_| RateLimited),
+            CommandPolicy::Reject => {
+                // Queued commands go first
+                if self.queue.capacity() < QUEUE_SIZE || !self.ratelimiter.try_acquire() {
+                    return Err(RateLimited);
+                }
+
+                let _res = self.sender.send(command);
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Update the presence of the shard on behalf of the proxy.
+    ///
+    /// The update is queued regardless of the configured policy. Returns false
+    /// if it could not be queued.
+    pub fn update_presence(&self, presence: UpdatePresencePayload) -> bool {
+        let command = UpdatePresence {
+            d: presence,
+            op: OpCode::PresenceUpdate,
+        };
+
+        to_string(&command).is_ok_and(|command| self.queue.try_send(command).is_ok())
+    }
+}
+
+/// Send queued commands to the shard as the rate limit allows.
+///
+/// This stops once the shard is dropped.
+async fn forward_commands(
+    mut rx: Receiver<String>,
+    ratelimiter: Arc<Ratelimiter>,
+    sender: MessageSender,
+) {
+    while let Some(command) = rx.recv().await {
+        ratelimiter.acquire().await;
+        let _res = sender.send(command);
+    }
+}
@@ -0,0 +1,191 @@
+#[cfg(not(feature = "simd-json"))]
+use serde_json::to_string;
+#[cfg(feature = "simd-json")]
+use simd_json::to_string;
+use tokio::time::Instant;
+use twilight_model::gateway::payload::outgoing::RequestGuildMembers;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{groups, model::RequestSoundboardSounds};
+
+/// Time after which the responses to a command are no longer expected.
+const RESPONSE_TIMEOUT: Duration = Duration::from_mins(1);
+
+/// A command that a session is waiting for the responses to.
+struct Pending {
+    session_id: String,
+    /// Nonce that the client sent, which the proxy replaced with its own.
+    nonce: Option<String>,
+    expires_at: Instant,
+}
+
+impl Pending {
+    fn new(session_id: &str, nonce: Option<String>) -> Self {
+        Self {
+            session_id: session_id.to_owned(),
+            nonce,
+            expires_at: Instant::now() + RESPONSE_TIMEOUT,
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// Commands that clients sent to a shard, so that their responses are only
+/// delivered to the session that sent them instead of all clients.
+pub struct Responses {
+    next_nonce: AtomicU64,
+    /// Member requests by the nonce that the proxy gave them.
+    members: Mutex<HashMap<String, Pending>>,
+    /// Sessions waiting for the soundboard sounds of a guild, oldest first.
+    soundboards: Mutex<HashMap<u64, VecDeque<Pending>>>,
+}
+
+impl Responses {
+    pub fn new() -> Self {
+        Self {
+            next_nonce: AtomicU64::new(0),
+            members: Mutex::new(HashMap::new()),
+            soundboards: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remember a command that a session is about to send to Discord and
+    /// return the payload to send instead.
+    ///
+    /// Member requests get a nonce that is unique to the proxy, since clients
+    /// may pick the same nonces. Soundboard requests carry no nonce, so their
+    /// responses go to the sessions in the order that they asked.
+    pub fn track(&self, session_id: &str, op: u8, payload: String) -> String {
+        let now = Instant::now();
+
+        match op {
+            8 => {
+                #[cfg(feature = "simd-json")]
+                let maybe_request =
+                    unsafe { simd_json::from_str::<RequestGuildMembers>(&mut payload.clone()) };
+                #[cfg(not(feature = "simd-json"))]
+                let maybe_request = serde_json::from_str::<RequestGuildMembers>(&payload);
+
+                let Ok(mut request) = maybe_request else {
+                    return payload;
+                };
+
+                let nonce = self.next_nonce.fetch_add(1, Ordering::Relaxed).to_string();
+                let original = request.d.nonce.replace(nonce.clone());
+
+                let Ok(rewritten) = to_string(&request) else {
+                    return payload;
+                };
+
+                let mut members = self.members.lock().unwrap();
+                members.retain(|_, pending| !pending.is_expired(now));
+                members.insert(nonce, Pending::new(session_id, original));
+                drop(members);
+
+                rewritten
+            }
+            31 => {
+                #[cfg(feature = "simd-json")]
+                let maybe_request =
+                    unsafe { simd_json::from_str::<RequestSoundboardSounds>(&mut payload.clone()) };
+                #[cfg(not(feature = "simd-json"))]
+                let maybe_request = serde_json::from_str::<RequestSoundboardSounds>(&payload);
+
+                if let Ok(request) = maybe_request {
+                    let mut soundboards = self.soundboards.lock().unwrap();
+
+                    soundboards.retain(|_, waiting| {
+                        waiting.retain(|pending| !pending.is_expired(now));
+                        !waiting.is_empty()
+                    });
+
+                    for guild_id in request.d.guild_ids {
+                        soundboards
+                            .entry(guild_id.get())
+                            .or_default()
+                            .push_back(Pending::new(session_id, None));
+                    }
+                }
+
+                payload
+            }
+            _ => payload,
+        }
+    }
+
+    /// Get the session that a dispatch is a response to, if it answers a
+    /// tracked command.
+    ///
+    /// The nonce that the client sent is put back into the payload.
+    pub fn route(
+        &self,
+        event_name: &str,
+        payload: &mut String,
+        guild_id: Option<u64>,
+    ) -> Option<String> {
+        let now = Instant::now();
+
+        match event_name {
+            "GUILD_MEMBERS_CHUNK" => {
+                let range = groups::find_string(payload, "nonce")?;
+
+                // The request is done once its last chunk arrived
+                let is_last = groups::find_integer(payload, "chunk_index")
+                    .zip(groups::find_integer(payload, "chunk_count"))
+                    .is_none_or(|(index, count)| index + 1 >= count);
+
+                let mut members = self.members.lock().unwrap();
+                let nonce = &payload[range.clone()];
+
+                let pending = members
+                    .get(nonce)
+                    .filter(|pending| !pending.is_expired(now))?;
+                let session_id = pending.session_id.clone();
+                let original = pending.nonce.clone();
+
+                if is_last {
+                    members.remove(nonce);
+                }
+
+                drop(members);
+
+                let original = original
+                    .and_then(|nonce| to_string(&nonce).ok())
+                    .unwrap_or_else(|| "null".to_owned());
+
+                // Replace the nonce including its quotes
+                payload.replace_range(range.start - 1..=range.end, &original);
+
+                Some(session_id)
+            }
+            "SOUNDBOARD_SOUNDS" => {
+                let guild_id = guild_id?;
+                let mut soundboards = self.soundboards.lock().unwrap();
+                let waiting = soundboards.get_mut(&guild_id)?;
+
+                let pending = std::iter::from_fn(|| waiting.pop_front())
+                    .find(|pending| !pending.is_expired(now));
+
+                if waiting.is_empty() {
+                    soundboards.remove(&guild_id);
+                }
+
+                drop(soundboards);
+
+                pending.map(|pending| pending.session_id)
+            }
+            _ => None,
+        }
+    }
+}
@@ -1,37 +1,68 @@
 use bytes::Bytes;
-use flate2::{Compress, Compression, FlushCompress, Status};
 use futures_util::{Sink, SinkExt, StreamExt};
 use http_body_util::Full;
-use hyper::{body::Incoming, service::service_fn, Method, Request, Response, StatusCode};
+use hyper::{
+    body::Incoming,
+    header::{HeaderValue, CONTENT_TYPE},
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
 use hyper_util::{
     rt::{TokioExecutor, TokioIo},
     server::conn::auto,
 };
 use itoa::Buffer;
 use metrics_exporter_prometheus::PrometheusHandle;
+use serde::Serialize;
 #[cfg(not(feature = "simd-json"))]
 use serde_json::{to_string, Value as OwnedValue};
 #[cfg(feature = "simd-json")]
 use simd_json::{to_string, OwnedValue};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
-    net::TcpListener,
-    sync::{
-        broadcast::error::RecvError,
-        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        oneshot,
-    },
+    net::{TcpListener, UnixListener},
+    sync::{broadcast::error::RecvError, oneshot},
+    time::{interval_at, sleep_until, timeout, Instant},
 };
-use tokio_websockets::{Error, Limits, Message, ServerBuilder};
+use tokio_websockets::{CloseCode, Error, Limits, Message, ServerBuilder};
 use tracing::{debug, error, info, trace, warn};
+use twilight_gateway::ShardState as ConnectionState;
+use twilight_model::gateway::{
+    payload::outgoing::{
+        update_presence::UpdatePresencePayload, RequestGuildMembers, UpdatePresence,
+    },
+    OpCode,
+};
 
-use std::{convert::Infallible, future::ready, net::SocketAddr, sync::Arc};
+use std::{
+    borrow::Cow,
+    convert::Infallible,
+    ffi::OsString,
+    fs::{
+        remove_dir_all, remove_file, rename, set_permissions, symlink_metadata, DirBuilder,
+        Permissions,
+    },
+    future::{pending, ready},
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{
-    config::CONFIG,
+    api,
+    auth::{self, Access},
+    compression::{Encoder, TransportCompression},
+    config::{self, LagPolicy, PresencePolicy, UnixSocket, CONFIG},
     deserializer::{GatewayEvent, SequenceInfo},
-    model::{Identify, Resume},
-    state::{Session, Shard, State},
+    dispatch::BroadcastMessage,
+    encoding::{etf_to_json, json_to_etf, Encoding},
+    model::{Identify, IdentifyPresence, Resume},
+    queue::{self, QueueFull},
+    state::{Address, Client, Inner, Session, Shard, State},
+    tls::Acceptor,
     upgrade,
 };
 
@@ -39,156 +70,394 @@ const HELLO: &str = r#"{"t":null,"s":null,"op":10,"d":{"heartbeat_interval":4125
 const HEARTBEAT_ACK: &str = r#"{"t":null,"s":null,"op":11,"d":null}"#;
 const INVALID_SESSION: &str = r#"{"t":null,"s":null,"op":9,"d":false}"#;
 const RESUMED: &str = r#"{"t":"RESUMED","s":null,"op":0,"d":{}}"#;
+const RECONNECT: &str = r#"{"t":null,"s":null,"op":7,"d":null}"#;
+
+/// Heartbeat interval that is sent to clients in HELLO.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(41250);
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_UNKNOWN_ERROR: u16 = 4000;
+const CLOSE_UNKNOWN_OPCODE: u16 = 4001;
+const CLOSE_DECODE_ERROR: u16 = 4002;
+const CLOSE_NOT_AUTHENTICATED: u16 = 4003;
+const CLOSE_AUTHENTICATION_FAILED: u16 = 4004;
+const CLOSE_ALREADY_AUTHENTICATED: u16 = 4005;
+const CLOSE_INVALID_SEQ: u16 = 4007;
+const CLOSE_RATE_LIMITED: u16 = 4008;
+const CLOSE_SESSION_TIMED_OUT: u16 = 4009;
+const CLOSE_INVALID_SHARD: u16 = 4010;
+const CLOSE_SHARDING_REQUIRED: u16 = 4011;
+const CLOSE_DISALLOWED_INTENTS: u16 = 4014;
+
+/// Time that the sink gets to send a close frame before it is torn down.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Create a close frame with a gateway close code.
+fn close_message(code: u16, reason: &str) -> Message {
+    // Gateway close codes are always in the valid range
+    Message::close(CloseCode::try_from(code).ok(), reason)
+}
 
-const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
-
-fn compress_full(compressor: &mut Compress, output: &mut Vec<u8>, input: &[u8]) {
-    let before_in = compressor.total_in() as usize;
-    while (compressor.total_in() as usize) - before_in < input.len() {
-        let offset = (compressor.total_in() as usize) - before_in;
-        match compressor
-            .compress_vec(&input[offset..], output, FlushCompress::None)
-            .unwrap()
-        {
-            Status::Ok => {}
-            Status::BufError => output.reserve(4096),
-            Status::StreamEnd => break,
-        }
+/// Convert a message to the encoding and compression of the client.
+///
+/// Returns [`None`] if the payload can not be transcoded to ETF.
+fn encode_message(
+    msg: Message,
+    encoding: Encoding,
+    encoder: Option<&mut Encoder>,
+) -> Option<Message> {
+    if msg.is_close() || msg.is_ping() {
+        return Some(msg);
     }
 
-    while !output.ends_with(&TRAILER) {
-        output.reserve(5);
-        if compressor
-            .compress_vec(&[], output, FlushCompress::Sync)
-            .unwrap()
-            == Status::StreamEnd
-        {
-            break;
-        }
+    let msg = if encoding == Encoding::Etf && msg.is_text() {
+        Message::binary(json_to_etf(&msg.into_payload())?)
+    } else {
+        msg
+    };
+
+    if let Some(encoder) = encoder {
+        Some(Message::binary(encoder.encode(&msg.into_payload())))
+    } else {
+        Some(msg)
     }
 }
 
 async fn sink_from_queue<S>(
-    addr: SocketAddr,
-    mut use_zlib: bool,
+    addr: Address,
+    encoding: Encoding,
+    compression: TransportCompression,
     compress_rx: oneshot::Receiver<Option<bool>>,
-    mut message_stream: UnboundedReceiver<Message>,
+    mut message_stream: queue::Receiver,
     mut sink: S,
 ) -> Result<(), Error>
 where
     S: Sink<Message, Error = Error> + Unpin + Send,
 {
-    // Initialize a zlib encoder with similar settings to Discord's
-    let mut compress = Compress::new(Compression::fast(), true);
-    let mut compression_buffer = Vec::with_capacity(32 * 1024);
+    let mut encoder = Encoder::new(compression);
 
     // At first, we will have to send a HELLO
-    if use_zlib {
-        compress_full(&mut compress, &mut compression_buffer, HELLO.as_bytes());
-
-        sink.send(Message::binary(Bytes::from(compression_buffer.clone())))
-            .await?;
-    } else {
-        sink.send(Message::text(HELLO.to_string())).await?;
+    if let Some(hello) =
+        encode_message(Message::text(HELLO.to_string()), encoding, encoder.as_mut())
+    {
+        sink.send(hello).await?;
     }
 
-    if compress_rx.await == Ok(Some(true)) {
-        use_zlib = true;
+    // Compression requested in IDENTIFY is always zlib
+    if encoder.is_none() && compress_rx.await == Ok(Some(true)) {
+        encoder = Encoder::new(TransportCompression::ZlibStream);
     }
 
     while let Some(msg) = message_stream.recv().await {
         trace!("[{addr}] Sending {msg:?}");
 
-        if use_zlib {
-            compression_buffer.clear();
-            compress_full(&mut compress, &mut compression_buffer, &msg.into_payload());
+        let is_close = msg.is_close();
 
-            sink.send(Message::binary(Bytes::from(compression_buffer.clone())))
-                .await?;
-        } else {
-            sink.send(msg).await?;
+        let Some(msg) = encode_message(msg, encoding, encoder.as_mut()) else {
+            warn!("[{addr}] Failed to transcode payload to ETF, skipping it");
+            continue;
+        };
+
+        sink.send(msg).await?;
+
+        // Nothing may be sent after a close frame
+        if is_close {
+            break;
         }
     }
 
     Ok(())
 }
 
+/// Send an event to the client if its intents allow it, overwriting its sequence number.
+async fn send_event(
+    session: &Session,
+    stream_writer: &queue::Sender,
+    buffer: &mut Buffer,
+    seq: &mut usize,
+    mut message: BroadcastMessage,
+) -> Result<(), QueueFull> {
+    if !session.wants(&message) {
+        return Ok(());
+    }
+
+    if let Some(SequenceInfo(_, sequence_range)) = message.sequence {
+        *seq += 1;
+        message
+            .payload
+            .replace_range(sequence_range, buffer.format(*seq));
+    }
+
+    stream_writer.send(Message::text(message.payload)).await
+}
+
+/// Forward the events of a shard to a client.
+///
+/// If `resume_index` is [`None`], the client gets a newly created `READY` and
+/// `GUILD_CREATE` payloads first, otherwise all events starting at that history
+/// index are replayed before the `RESUMED`.
 async fn forward_shard(
-    session_id: String,
+    inner: Arc<Inner>,
+    session: Arc<Session>,
     shard_status: Arc<Shard>,
-    stream_writer: UnboundedSender<Message>,
-    send_guilds: bool,
-    mut seq: usize,
+    stream_writer: queue::Sender,
+    resume_index: Option<u64>,
+    seq: usize,
 ) {
     let shard_id = shard_status.id;
 
     debug!("[Shard {shard_id}] Starting to send events to client",);
 
+    stream_writer.track(shard_id);
+    session.track_queue(stream_writer.depth());
+
+    if forward_events(
+        &inner,
+        &session,
+        &shard_status,
+        &stream_writer,
+        resume_index,
+        seq,
+    )
+    .await
+    .is_err()
+    {
+        // The client resumes and gets the events from the history
+        warn!("[Shard {shard_id}] Client queue is full, disconnecting");
+        metrics::counter!("gateway_client_queue_overflows", "shard" => shard_id.to_string())
+            .increment(1);
+        stream_writer.send_control(close_message(CLOSE_UNKNOWN_ERROR, "Client fell behind."));
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+async fn forward_events(
+    inner: &Inner,
+    session: &Session,
+    shard_status: &Shard,
+    stream_writer: &queue::Sender,
+    resume_index: Option<u64>,
+    mut seq: usize,
+) -> Result<(), QueueFull> {
+    let shard_id = shard_status.id;
+
     // Wait until we have a valid READY payload for this shard
     let ready_payload = shard_status.ready.wait_until_ready().await;
 
-    if send_guilds {
+    // For formatting the sequence number as a string, reuse a buffer
+    let mut buffer = Buffer::new();
+
+    let mut next_index = if let Some(resume_index) = resume_index {
+        resume_index
+    } else {
+        // Events are applied to the cache before they are added to the
+        // history, so every event from this index on is either part of the
+        // cached payloads below or recovered from the history
+        let next_index = shard_status.history.next_index();
+
         // Get a fake ready payload to send to the client
         let mut ready_payload = shard_status
             .guilds
             .get_ready_payload(ready_payload, &mut seq);
 
         // Overwrite the session ID in the READY
-        ready_payload
-            .d
-            .insert(String::from("session_id"), OwnedValue::String(session_id));
+        ready_payload.d.insert(
+            String::from("session_id"),
+            OwnedValue::String(session.id.clone()),
+        );
+
+        // Override resume_gateway_url with the external URI of the proxy
+        ready_payload.d.insert(
+            String::from("resume_gateway_url"),
+            config::current().externally_accessible_url.clone().into(),
+        );
 
         if let Ok(serialized) = to_string(&ready_payload) {
             debug!("[Shard {shard_id}] Sending newly created READY");
-            let _res = stream_writer.send(Message::text(serialized));
-        };
+            stream_writer.send(Message::text(serialized)).await?;
+        }
 
         // Send GUILD_CREATE/GUILD_DELETEs based on guild availability
-        for payload in shard_status.guilds.get_guild_payloads(&mut seq) {
+        for payload in shard_status
+            .guilds
+            .get_guild_payloads(session.intents, &mut seq)
+        {
             trace!("[Shard {shard_id}] Sending newly created GUILD_CREATE/GUILD_DELETE payload");
-            let _res = stream_writer.send(Message::text(payload));
+            stream_writer.send(Message::text(payload)).await?;
         }
-    } else {
-        let _res = stream_writer.send(Message::text(RESUMED.to_string()));
-    }
 
-    // For formatting the sequence number as a string, reuse a buffer
-    let mut buffer = Buffer::new();
+        // Voice connections of an earlier session of this client continue on this one
+        for message in shard_status.voice.adopt(inner, session) {
+            send_event(session, stream_writer, &mut buffer, &mut seq, message).await?;
+        }
+
+        next_index
+    };
 
     // Subscribe to events for this shard
     let mut event_receiver = shard_status.events.subscribe();
 
-    loop {
-        let res = event_receiver.recv().await;
+    if resume_index.is_some() {
+        // Events that are broadcast after we subscribe and are also part of
+        // the replay are skipped below
+        let Some(missed) = shard_status.history.since(next_index) else {
+            // The client already counts as resumed, so it has to reconnect
+            // and identify with a new session
+            debug!("[Shard {shard_id}] Events to replay are no longer in the history");
+            stream_writer
+                .send_control(close_message(CLOSE_SESSION_TIMED_OUT, "Session timed out."));
+            return Ok(());
+        };
+
+        debug!("[Shard {shard_id}] Replaying {} events", missed.len());
 
-        if let Ok((mut payload, sequence)) = res {
-            // Overwrite the sequence number
-            if let Some(SequenceInfo(_, sequence_range)) = sequence {
-                seq += 1;
-                payload.replace_range(sequence_range, buffer.format(seq));
+        for message in missed {
+            next_index = message.index + 1;
+            send_event(session, stream_writer, &mut buffer, &mut seq, message).await?;
+        }
+
+        stream_writer.send_control(Message::text(RESUMED.to_string()));
+    }
+
+    session.set_position(seq, next_index, false);
+
+    loop {
+        let message = match event_receiver.recv().await {
+            Ok(message) => message,
+            Err(RecvError::Lagged(amt)) => {
+                metrics::counter!("gateway_client_lagged_events", "shard" => shard_id.to_string())
+                    .increment(amt);
+                session.add_lagged_events(amt);
+
+                match config::current().lag_policy {
+                    LagPolicy::Ignore => {
+                        warn!("[Shard {shard_id}] Client is {amt} events behind!");
+                        continue;
+                    }
+                    LagPolicy::Resume => {
+                        // The client resumes from the last event it got and
+                        // the missed ones are replayed from the history
+                        warn!("[Shard {shard_id}] Client is {amt} events behind, requesting it to resume");
+                        stream_writer.send_control(close_message(
+                            CLOSE_UNKNOWN_ERROR,
+                            "Client fell behind.",
+                        ));
+                        return Ok(());
+                    }
+                    LagPolicy::Invalidate => {
+                        // The client identifies again and gets a fresh READY
+                        // and GUILD_CREATEs
+                        warn!("[Shard {shard_id}] Client is {amt} events behind, invalidating its session");
+                        stream_writer
+                            .send_control(close_message(CLOSE_INVALID_SEQ, "Invalid seq."));
+                        inner.kick_session(&session.id);
+                        return Ok(());
+                    }
+                }
+            }
+            Err(RecvError::Closed) => {
+                // The shard was replaced after resharding
+                debug!("[Shard {shard_id}] Shard was retired, requesting client to reconnect");
+                stream_writer.send_control(Message::text(RECONNECT.to_string()));
+                return Ok(());
             }
+        };
+
+        if message.index < next_index {
+            // Already sent during the replay
+            continue;
+        }
+
+        let mut contiguous = true;
 
-            let _res = stream_writer.send(Message::text(payload));
-        } else if let Err(RecvError::Lagged(amt)) = res {
-            warn!("[Shard {shard_id}] Client is {amt} events behind!");
+        if message.index > next_index {
+            // The client missed some events, send them from the history if possible
+            if let Some(missed) = shard_status.history.since(next_index) {
+                for missed in missed.into_iter().take_while(|m| m.index < message.index) {
+                    send_event(session, stream_writer, &mut buffer, &mut seq, missed).await?;
+                }
+            } else {
+                warn!("[Shard {shard_id}] Events missed by client are no longer in the history");
+                contiguous = false;
+            }
         }
+
+        next_index = message.index + 1;
+        send_event(session, stream_writer, &mut buffer, &mut seq, message).await?;
+        session.set_position(seq, next_index, contiguous);
     }
 }
 
+/// Answer a Request Guild Members command from the cache.
+///
+/// Returns false if the cache can not answer it, so that it has to be sent to
+/// Discord instead.
+fn request_guild_members(session: &Session, shard: &Shard, payload: &str) -> bool {
+    #[cfg(feature = "simd-json")]
+    let maybe_request =
+        unsafe { simd_json::from_str::<RequestGuildMembers>(&mut payload.to_owned()) };
+    #[cfg(not(feature = "simd-json"))]
+    let maybe_request = serde_json::from_str::<RequestGuildMembers>(payload);
+
+    let Some(chunks) = maybe_request
+        .ok()
+        .and_then(|request| shard.guilds.get_member_chunks(request.d, session.intents))
+    else {
+        metrics::counter!("gateway_member_requests", "source" => "discord").increment(1);
+        return false;
+    };
+
+    metrics::counter!("gateway_member_requests", "source" => "cache").increment(1);
+
+    for chunk in chunks {
+        shard.send_to_session(&session.id, chunk);
+    }
+
+    true
+}
+
+/// Whether a presence update from a client should be sent to Discord, which
+/// makes it the presence of the shard.
+///
+/// Updates that the proxy can not read are sent as they are, unless clients
+/// may not change the presence at all.
+fn accept_presence(
+    inner: &Inner,
+    session: &Session,
+    shard: &Shard,
+    presence: Option<UpdatePresencePayload>,
+) -> bool {
+    let policy = config::current().presence_policy;
+
+    let Some(presence) = presence else {
+        return policy != PresencePolicy::Config;
+    };
+
+    shard
+        .presence
+        .update(&session.id, presence, policy, |session_id| {
+            inner.get_session(session_id).is_some()
+        })
+}
+
 #[allow(clippy::too_many_lines)]
 pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
-    addr: SocketAddr,
+    addr: Address,
     stream: S,
     state: State,
-    use_zlib: bool,
+    encoding: Encoding,
+    compression: TransportCompression,
+    group: Option<String>,
 ) -> Result<(), Error> {
     // We use a oneshot channel to tell the forwarding task whether the IDENTIFY
     // contained a compression request
     let (compress_tx, compress_rx) = oneshot::channel();
     let mut compress_tx = Some(compress_tx);
 
-    // We need to know which shard this client is connected to in order to send messages to it
-    let mut shard_sender = None;
+    // What the client's token allows it to do, known after IDENTIFY or RESUME
+    let mut access: Option<Access> = None;
+
+    let client = Client::new(addr, compression);
 
     let ws_conn = ServerBuilder::new()
         .limits(Limits::unlimited())
@@ -197,11 +466,12 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
     let (sink, mut stream) = ws_conn.split();
 
     // Write all messages from a queue to the sink
-    let (stream_writer, stream_receiver) = unbounded_channel::<Message>();
+    let (stream_writer, stream_receiver) = queue::channel(&config::current().client_queue);
 
-    let sink_task = tokio::spawn(sink_from_queue(
+    let mut sink_task = tokio::spawn(sink_from_queue(
         addr,
-        use_zlib,
+        encoding,
+        compression,
         compress_rx,
         stream_receiver,
         sink,
@@ -209,24 +479,143 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
 
     let mut shard_forward_task = None;
 
-    while let Some(Ok(msg)) = stream.next().await {
+    // The session this client identified or resumed with
+    let mut current_session: Option<(Arc<Session>, Arc<Shard>)> = None;
+
+    // Whether a close frame was queued for the client
+    let mut closing = false;
+
+    // Clients have to heartbeat within the interval from HELLO
+    let heartbeat_timeout = HEARTBEAT_INTERVAL + config::current().heartbeat_grace();
+    let mut last_heartbeat = Instant::now();
+
+    // Websocket pings detect dead peers. They are only sent once the client
+    // has a session, because nothing is written to the client before that
+    let ping_interval = config::current().ping_interval();
+    let mut ping_timer = None;
+    let mut awaiting_pong = false;
+
+    loop {
+        if ping_timer.is_none() && current_session.is_some() {
+            ping_timer = ping_interval.map(|period| interval_at(Instant::now() + period, period));
+        }
+
+        let kicked = async {
+            match &current_session {
+                Some((session, _)) => session.wait_kicked(client.id).await,
+                None => pending().await,
+            }
+        };
+
+        let ping = async {
+            match &mut ping_timer {
+                Some(ping_timer) => {
+                    ping_timer.tick().await;
+                }
+                None => pending().await,
+            }
+        };
+
+        let msg = tokio::select! {
+            msg = stream.next() => msg,
+            () = kicked => {
+                info!("[{addr}] Session was kicked, disconnecting");
+                stream_writer.send_control(close_message(CLOSE_NORMAL, "Session was kicked."));
+                closing = true;
+                break;
+            }
+            () = sleep_until(last_heartbeat + heartbeat_timeout) => {
+                warn!("[{addr}] Client did not heartbeat in time, disconnecting");
+                metrics::counter!("gateway_client_zombies", "reason" => "heartbeat").increment(1);
+                stream_writer.send_control(close_message(CLOSE_UNKNOWN_ERROR, "Session timed out."));
+                closing = true;
+                break;
+            }
+            () = ping => {
+                if awaiting_pong {
+                    warn!("[{addr}] Client did not answer ping, disconnecting");
+                    metrics::counter!("gateway_client_zombies", "reason" => "ping").increment(1);
+                    stream_writer.send_control(close_message(CLOSE_UNKNOWN_ERROR, "Session timed out."));
+                    closing = true;
+                    break;
+                }
+
+                awaiting_pong = true;
+                stream_writer.send_control(Message::ping(Bytes::new()));
+                continue;
+            }
+        };
+
+        let Some(Ok(msg)) = msg else {
+            break;
+        };
+
+        // Anything that the client sends shows that it is alive
+        awaiting_pong = false;
+
         if !msg.is_text() && !msg.is_binary() {
             continue;
         }
 
+        let text = if encoding == Encoding::Etf {
+            let Some(json) = etf_to_json(msg.as_payload()) else {
+                warn!("[{addr}] Invalid ETF payload, disconnecting");
+                stream_writer.send_control(close_message(
+                    CLOSE_DECODE_ERROR,
+                    "Error while decoding payload.",
+                ));
+                closing = true;
+                break;
+            };
+
+            Cow::Owned(json)
+        } else if let Some(text) = msg.as_text() {
+            Cow::Borrowed(text)
+        } else {
+            warn!("[{addr}] Binary payload without ETF encoding, disconnecting");
+            stream_writer.send_control(close_message(
+                CLOSE_DECODE_ERROR,
+                "Error while decoding payload.",
+            ));
+            closing = true;
+            break;
+        };
+
         #[cfg(feature = "simd-json")]
-        let mut payload = unsafe { msg.as_text().unwrap_unchecked().to_owned() };
+        let mut payload = text.into_owned();
         #[cfg(not(feature = "simd-json"))]
-        let payload = unsafe { msg.as_text().unwrap_unchecked() };
+        let payload = text;
 
         let Some(deserializer) = GatewayEvent::from_json(&payload) else {
-            continue;
+            warn!("[{addr}] Invalid payload, disconnecting");
+            stream_writer.send_control(close_message(
+                CLOSE_DECODE_ERROR,
+                "Error while decoding payload.",
+            ));
+            closing = true;
+            break;
         };
 
-        match deserializer.op() {
+        if let Some((session, _)) = &current_session {
+            session.touch();
+        }
+
+        let op = deserializer.op();
+
+        match op {
             1 => {
+                last_heartbeat = Instant::now();
                 trace!("[{addr}] Sending heartbeat ACK");
-                let _res = stream_writer.send(Message::text(HEARTBEAT_ACK.to_string()));
+                stream_writer.send_control(Message::text(HEARTBEAT_ACK.to_string()));
+            }
+            2 | 6 if current_session.is_some() => {
+                warn!("[{addr}] Client attempted to authenticate twice, disconnecting");
+                stream_writer.send_control(close_message(
+                    CLOSE_ALREADY_AUTHENTICATED,
+                    "Already authenticated.",
+                ));
+                closing = true;
+                break;
             }
             2 => {
                 debug!("[{addr}] Client is identifying");
@@ -239,50 +628,146 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                 let identify: Identify = match maybe_identify {
                     Ok(identify) => identify,
                     Err(e) => {
-                        warn!("[{addr}] Invalid identify payload: {e:?}");
-                        continue;
+                        warn!("[{addr}] Invalid identify payload, disconnecting: {e:?}");
+                        stream_writer.send_control(close_message(
+                            CLOSE_DECODE_ERROR,
+                            "Error while decoding payload.",
+                        ));
+                        closing = true;
+                        break;
                     }
                 };
 
-                let (shard_id, shard_count) = (identify.d.shard[0], identify.d.shard[1]);
+                // Shards may be replaced after resharding, so use the current ones
+                let inner = state.get();
+
+                // Like Discord, only allow leaving out the shard if there is a single one
+                let Some([shard_id, shard_count]) = identify
+                    .d
+                    .shard
+                    .or_else(|| (inner.shard_count == 1).then_some([0, 1]))
+                else {
+                    warn!("[{addr}] Client identified without a shard, disconnecting");
+                    stream_writer
+                        .send_control(close_message(CLOSE_SHARDING_REQUIRED, "Sharding required."));
+                    closing = true;
+                    break;
+                };
 
-                if shard_count != state.shard_count {
-                    warn!("[{addr}] Shard count from client identify mismatched, disconnecting",);
+                if shard_count != inner.shard_count {
+                    warn!(
+                        "[{addr}] Client identified with {shard_count} shards instead of {}, disconnecting",
+                        inner.shard_count
+                    );
+                    stream_writer.send_control(close_message(
+                        CLOSE_INVALID_SHARD,
+                        "Invalid shard count, fetch it from /shard-count again.",
+                    ));
+                    closing = true;
                     break;
                 }
 
                 if shard_id >= shard_count {
                     warn!("[{addr}] Shard ID from client is out of range, disconnecting",);
+                    stream_writer
+                        .send_control(close_message(CLOSE_INVALID_SHARD, "Invalid shard."));
+                    closing = true;
                     break;
                 }
 
-                // Discord tokens may be prefixed by 'Bot ' in IDENTIFY
-                if CONFIG.validate_token
-                    && identify.d.token.split_whitespace().last() != Some(&CONFIG.token)
-                {
+                let Some(identify_access) = auth::authenticate(&identify.d.token) else {
                     warn!("[{addr}] Token from client mismatched, disconnecting");
+                    stream_writer.send_control(close_message(
+                        CLOSE_AUTHENTICATION_FAILED,
+                        "Authentication failed.",
+                    ));
+                    closing = true;
+                    break;
+                };
+
+                if !identify_access.allows_shard(shard_id) {
+                    warn!("[{addr}] Credential from client is not allowed to use shard {shard_id}, disconnecting");
+                    stream_writer
+                        .send_control(close_message(CLOSE_INVALID_SHARD, "Invalid shard."));
+                    closing = true;
+                    break;
+                }
+
+                // Clients can only receive events for intents that the proxy identified with
+                let intents = identify.d.intents.unwrap_or(CONFIG.intents);
+
+                if !CONFIG.intents.contains(intents) {
+                    warn!("[{addr}] Intents from client are not allowed, disconnecting");
+                    stream_writer.send_control(close_message(
+                        CLOSE_DISALLOWED_INTENTS,
+                        "Disallowed intent(s).",
+                    ));
+                    closing = true;
                     break;
                 }
 
                 trace!("[{addr}] Shard ID is {shard_id}");
 
-                // Create a new session for this client
-                let session = Session {
-                    shard_id,
-                    compress: identify.d.compress,
+                // The client is connected to this shard, so prepare for sending commands to it
+                let Some(shard) = inner.shard(shard_id) else {
+                    warn!(
+                        "[{addr}] Shard ID from client is not managed by the proxy, disconnecting"
+                    );
+                    stream_writer
+                        .send_control(close_message(CLOSE_INVALID_SHARD, "Invalid shard."));
+                    closing = true;
+                    break;
                 };
-                let session_id = state.create_session(session);
+                let credential = identify_access.name().map(ToString::to_string);
+                access = Some(identify_access);
 
-                // The client is connected to this shard, so prepare for sending commands to it
-                let shard = state.shards[shard_id as usize].clone();
-                shard_sender = Some(shard.sender.clone());
+                // Workers can join a group through the query string or IDENTIFY
+                let group = group.clone().or_else(|| {
+                    identify
+                        .d
+                        .properties
+                        .and_then(|properties| properties.group)
+                });
 
                 if let Some(sender) = compress_tx.take() {
+                    // Create a new session for this client
+                    let session = inner.create_session(Session::new(
+                        shard_id,
+                        identify.d.compress,
+                        intents,
+                        group,
+                        credential,
+                        client,
+                    ));
+                    shard.groups.join(shard_id, &session);
+                    current_session = Some((session.clone(), shard.clone()));
+
+                    // The presence from IDENTIFY is only used if enabled, since most
+                    // clients send one whenever they connect
+                    if let Some(IdentifyPresence::Valid(presence)) = identify.d.presence {
+                        if config::current().identify_presence
+                            && accept_presence(&inner, &session, &shard, Some(presence.clone()))
+                        {
+                            let command = UpdatePresence {
+                                d: presence,
+                                op: OpCode::PresenceUpdate,
+                            };
+
+                            let sent = to_string(&command)
+                                .is_ok_and(|command| shard.commands.send(command).is_ok());
+
+                            if !sent {
+                                warn!("[{addr}] Failed to send presence from IDENTIFY");
+                            }
+                        }
+                    }
+
                     shard_forward_task = Some(tokio::spawn(forward_shard(
-                        session_id,
+                        inner.clone(),
+                        session,
                         shard,
                         stream_writer.clone(),
-                        true,
+                        None,
                         0,
                     )));
 
@@ -300,67 +785,217 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                 let resume: Resume = match maybe_resume {
                     Ok(resume) => resume,
                     Err(e) => {
-                        warn!("[{addr}] Invalid resume payload: {e:?}");
-                        continue;
+                        warn!("[{addr}] Invalid resume payload, disconnecting: {e:?}");
+                        stream_writer.send_control(close_message(
+                            CLOSE_DECODE_ERROR,
+                            "Error while decoding payload.",
+                        ));
+                        closing = true;
+                        break;
                     }
                 };
 
-                // Discord tokens may be prefixed by 'Bot ' in RESUME
-                if CONFIG.validate_token
-                    && resume.d.token.split_whitespace().last() != Some(&CONFIG.token)
-                {
+                let Some(resume_access) = auth::authenticate(&resume.d.token) else {
                     warn!("[{addr}] Token from client mismatched, disconnecting");
+                    stream_writer.send_control(close_message(
+                        CLOSE_AUTHENTICATION_FAILED,
+                        "Authentication failed.",
+                    ));
+                    closing = true;
                     break;
-                }
+                };
+
+                // Find the shard that has the matching session ID and check whether
+                // the events after the client's sequence number can be replayed.
+                // Sessions can only be resumed with the credential they were created with.
+                let inner = state.get();
+                let resumable = inner.get_session(&resume.d.session_id).and_then(|session| {
+                    if session.credential.as_deref() != resume_access.name()
+                        || !resume_access.allows_shard(session.shard_id)
+                    {
+                        return None;
+                    }
 
-                // Find the shard that has the matching session ID
-                if let Some(session) = state.get_session(&resume.d.session_id) {
-                    let session_id = resume.d.session_id;
-                    debug!("[{addr}] Successfully resuming session {session_id}",);
+                    let shard = inner.shard(session.shard_id)?;
+                    Some((
+                        session.resume_index(resume.d.seq, &shard.history)?,
+                        session,
+                        shard,
+                    ))
+                });
+
+                if let Some((resume_index, session, shard)) = resumable {
+                    debug!("[{addr}] Successfully resuming session {}", session.id);
 
-                    let shard = state.shards[session.shard_id as usize].clone();
+                    access = Some(resume_access);
 
                     if let Some(sender) = compress_tx.take() {
+                        let compress = session.compress;
+
+                        // Only one client can be connected to a session at a time
+                        if session.is_connected() {
+                            debug!(
+                                "[{addr}] Disconnecting previous client of session {}",
+                                session.id
+                            );
+                            session.kick();
+                        }
+
+                        session.attach(client);
+                        shard.groups.join(session.shard_id, &session);
+                        current_session = Some((session.clone(), shard.clone()));
+
                         shard_forward_task = Some(tokio::spawn(forward_shard(
-                            session_id,
-                            shard.clone(),
+                            inner.clone(),
+                            session,
+                            shard,
                             stream_writer.clone(),
-                            false,
+                            Some(resume_index),
                             resume.d.seq,
                         )));
 
-                        let _res = sender.send(session.compress);
-                    } else {
-                        let _res = stream_writer.send(Message::text(INVALID_SESSION.to_string()));
+                        let _res = sender.send(compress);
                     }
                 } else {
-                    let _res = stream_writer.send(Message::text(INVALID_SESSION.to_string()));
+                    stream_writer.send_control(Message::text(INVALID_SESSION.to_string()));
                 }
             }
             _ => {
-                if let Some(sender) = &shard_sender {
-                    trace!("[{addr}] Sending {payload:?} to Discord directly");
-                    let _res = sender.send(payload.to_string());
-                } else {
-                    warn!("[{addr}] Client attempted to send payload before IDENTIFY",);
+                if access
+                    .as_ref()
+                    .is_some_and(|access| !access.allows_opcode(op))
+                {
+                    warn!("[{addr}] Credential from client is not allowed to send op {op}, disconnecting");
+                    stream_writer
+                        .send_control(close_message(CLOSE_UNKNOWN_OPCODE, "Opcode not allowed."));
+                    closing = true;
+                    break;
+                }
+
+                // We need to know which shard this client is connected to in order to send messages to it
+                let Some((session, shard)) = &current_session else {
+                    warn!(
+                        "[{addr}] Client attempted to send payload before IDENTIFY, disconnecting",
+                    );
+                    stream_writer
+                        .send_control(close_message(CLOSE_NOT_AUTHENTICATED, "Not authenticated."));
+                    closing = true;
+                    break;
+                };
+
+                // Member requests are answered from the cache if it has all members
+                if op == 8 && request_guild_members(session, shard, &payload) {
+                    trace!("[{addr}] Answered member request from the cache");
+                    continue;
+                }
+
+                // Commands of all clients count towards the rate limit of the shard
+                if op == 3 {
+                    #[cfg(feature = "simd-json")]
+                    let maybe_update =
+                        unsafe { simd_json::from_str::<UpdatePresence>(&mut payload.clone()) };
+                    #[cfg(not(feature = "simd-json"))]
+                    let maybe_update = serde_json::from_str::<UpdatePresence>(&payload);
+
+                    let presence = maybe_update.ok().map(|update| update.d);
+
+                    if !accept_presence(&state.get(), session, shard, presence) {
+                        debug!("[{addr}] Presence update was not sent to Discord");
+                        continue;
+                    }
                 }
+
+                if op == 4 {
+                    shard.voice.track(session, &payload);
+                }
+
+                // Responses to commands are tracked so that they only go to this session
+                let payload = shard
+                    .responses
+                    .track(&session.id, op, (*payload).to_owned());
+                trace!("[{addr}] Sending {payload:?} to Discord");
+
+                if shard.commands.send(payload).is_err() {
+                    warn!("[{addr}] Client exceeded the command rate limit, disconnecting");
+                    metrics::counter!("gateway_client_commands_rejected", "shard" => shard.id.to_string()).increment(1);
+                    stream_writer.send_control(close_message(CLOSE_RATE_LIMITED, "Rate limited."));
+                    closing = true;
+                    break;
+                }
+
+                metrics::counter!("gateway_client_commands", "shard" => shard.id.to_string(), "op" => op.to_string()).increment(1);
+                session.add_command();
             }
         }
     }
 
     debug!("[{addr}] Client disconnected");
 
+    if closing {
+        // The sink waits for IDENTIFY or RESUME before sending anything
+        compress_tx.take();
+
+        // Give the sink a chance to send the close frame
+        let _res = timeout(CLOSE_TIMEOUT, &mut sink_task).await;
+    }
+
     sink_task.abort();
 
     if let Some(shard_forward_task) = shard_forward_task {
         shard_forward_task.abort();
     }
 
+    // The session can be resumed until it expires, while its share of the
+    // events goes to the rest of its worker group
+    if let Some((session, shard)) = current_session {
+        if session.detach(client.id) {
+            shard.groups.leave(session.shard_id, &session);
+        }
+    }
+
     Ok(())
 }
 
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    not_ready: Vec<u32>,
+}
+
+/// Check whether every shard has a READY payload and an active connection.
+fn readiness(state: &State) -> Response<Full<Bytes>> {
+    let not_ready: Vec<_> = state
+        .get()
+        .shards
+        .iter()
+        .filter(|shard| {
+            !shard.ready.is_ready()
+                || shard.status.lock().unwrap().connection != ConnectionState::Active
+        })
+        .map(|shard| shard.id)
+        .collect();
+
+    let status = if not_ready.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let body = to_string(&Readiness {
+        ready: not_ready.is_empty(),
+        not_ready,
+    })
+    .unwrap_or_default();
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .body(Full::from(body))
+        .unwrap()
+}
+
 fn handler(
-    addr: SocketAddr,
+    addr: Address,
     request: Request<Incoming>,
     state: State,
     metrics: &PrometheusHandle,
@@ -370,9 +1005,18 @@ fn handler(
             .status(StatusCode::OK)
             .body(Full::from(metrics.render()))
             .unwrap(),
+        // Requests are only handled once the listener is bound
+        (&Method::GET, "/healthz") => Response::builder()
+            .status(StatusCode::OK)
+            .body(Full::from("ok"))
+            .unwrap(),
+        (&Method::GET, "/readyz") => readiness(&state),
+        (&Method::GET, path) if path.starts_with("/guilds/") && config::current().cache_api => {
+            api::handler(&request, &state)
+        }
         (&Method::GET, "/shard-count") => {
             let mut buffer = itoa::Buffer::new();
-            let shard_count_str = buffer.format(state.shard_count);
+            let shard_count_str = buffer.format(state.get().shard_count);
 
             Response::builder()
                 .status(StatusCode::OK)
@@ -385,7 +1029,34 @@ fn handler(
     }
 }
 
-pub async fn run(port: u16, state: State, metrics_handle: PrometheusHandle) -> Result<(), Error> {
+async fn serve_connection<IO>(io: IO, addr: Address, state: State, metrics_handle: PrometheusHandle)
+where
+    IO: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if let Err(e) = auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(
+            TokioIo::new(io),
+            service_fn(move |incoming: Request<Incoming>| {
+                ready(Ok::<_, Infallible>(handler(
+                    addr,
+                    incoming,
+                    state.clone(),
+                    &metrics_handle,
+                )))
+            }),
+        )
+        .await
+    {
+        error!("Error handling connection: {e}");
+    }
+}
+
+pub async fn run(
+    port: u16,
+    state: State,
+    metrics_handle: PrometheusHandle,
+    tls: Option<Arc<Acceptor>>,
+) -> Result<(), Error> {
     let addr: SocketAddr = ([0, 0, 0, 0], port).into();
 
     let listener = match TcpListener::bind(addr).await {
@@ -407,28 +1078,133 @@ pub async fn run(port: u16, state: State, metrics_handle: PrometheusHandle) -> R
             }
         };
 
-        trace!("[{addr:?}] New connection");
+        let addr = Address::Tcp(addr);
+
+        trace!("[{addr}] New connection");
 
         let state = state.clone();
         let metrics_handle = metrics_handle.clone();
 
+        let tls = tls.clone();
+
         tokio::spawn(async move {
-            if let Err(e) = auto::Builder::new(TokioExecutor::new())
-                .serve_connection_with_upgrades(
-                    TokioIo::new(conn),
-                    service_fn(move |incoming: Request<Incoming>| {
-                        ready(Ok::<_, Infallible>(handler(
-                            addr,
-                            incoming,
-                            state.clone(),
-                            &metrics_handle,
-                        )))
-                    }),
-                )
-                .await
-            {
-                error!("Error handling connection: {e}");
+            if let Some(acceptor) = tls {
+                match acceptor.get().accept(conn).await {
+                    Ok(stream) => serve_connection(stream, addr, state, metrics_handle).await,
+                    Err(e) => debug!("[{addr}] TLS handshake failed: {e}"),
+                }
+            } else {
+                serve_connection(conn, addr, state, metrics_handle).await;
             }
         });
     }
 }
+
+/// Serve the gateway on a Unix domain socket for clients on the same host.
+/// Bind a Unix listener at `path` that has the file `mode` from the start.
+///
+/// The socket is created in a private directory, given its mode and only then
+/// moved to `path`, so that no client can connect before the mode applies.
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    let mut staging_name = OsString::from(".");
+    staging_name.push(path.file_name().unwrap_or_default());
+    staging_name.push(".tmp");
+
+    let staging_dir = path.with_file_name(staging_name);
+
+    // The directory is left over if a previous run was killed while binding
+    if let Err(e) = remove_dir_all(&staging_dir) {
+        if e.kind() != ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+
+    DirBuilder::new().mode(0o700).create(&staging_dir)?;
+
+    let staged = staging_dir.join("socket");
+
+    let result = UnixListener::bind(&staged).and_then(|listener| {
+        if let Some(mode) = mode {
+            set_permissions(&staged, Permissions::from_mode(mode))?;
+        }
+
+        rename(&staged, path)?;
+
+        Ok(listener)
+    });
+
+    let _res = remove_dir_all(&staging_dir);
+
+    result
+}
+
+pub async fn run_unix(unix_socket: &UnixSocket, state: State, metrics_handle: PrometheusHandle) {
+    let path = Path::new(&unix_socket.path);
+
+    let permissions = unix_socket.permissions.as_deref();
+
+    let Ok(mode) = permissions
+        .map(|permissions| u32::from_str_radix(permissions, 8))
+        .transpose()
+    else {
+        error!(
+            "Unix socket permissions {} are not an octal mode",
+            permissions.unwrap_or_default()
+        );
+        return;
+    };
+
+    // A socket file that is left over from a previous run would make binding
+    // fail, but anything else at the path is not ours to remove
+    match symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if let Err(e) = remove_file(path) {
+                error!("Failed to remove old Unix socket {}: {e}", unix_socket.path);
+                return;
+            }
+        }
+        Ok(_) => {
+            error!(
+                "{} exists and is not a Unix socket, refusing to replace it",
+                unix_socket.path
+            );
+            return;
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => {
+            error!("Failed to check Unix socket path {}: {e}", unix_socket.path);
+            return;
+        }
+    }
+
+    let listener = match bind_unix(path, mode) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind Unix listener: {e}");
+            return;
+        }
+    };
+
+    info!("Listening on {}", unix_socket.path);
+
+    loop {
+        let conn = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Failed to accept Unix connection: {e}");
+                return;
+            }
+        };
+
+        let addr = Address::Unix(conn.peer_cred().ok().and_then(|cred| cred.pid()));
+
+        trace!("[{addr}] New connection");
+
+        tokio::spawn(serve_connection(
+            conn,
+            addr,
+            state.clone(),
+            metrics_handle.clone(),
+        ));
+    }
+}
@@ -1,13 +1,34 @@
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use tokio::sync::{broadcast, Notify};
-use twilight_gateway::MessageSender;
+use tokio::{
+    pin,
+    sync::{broadcast, Notify},
+    task::JoinSet,
+    time::{interval, Instant},
+};
+use tracing::debug;
+use twilight_gateway::{CloseFrame, Intents, MessageSender, ShardState as ConnectionState};
 
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    collections::{HashMap, VecDeque},
+    fmt::{Display, Formatter, Result as FmtResult},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    cache, compression::TransportCompression, config, dispatch::BroadcastMessage, groups::Groups,
+    model::JsonObject, persistence::SavedShard, presence::Presence, queue, ratelimit::Commands,
+    responses::Responses, voice::Voice,
 };
 
-use crate::{cache, dispatch::BroadcastMessage, model::JsonObject};
+const SESSION_COLLECT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// ID of the next client connection.
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(0);
 
 /// Manager for the READY state of a shard.
 pub struct Ready {
@@ -31,6 +52,11 @@ impl Ready {
         self.inner.read().unwrap().is_some()
     }
 
+    /// Get the READY payload, if the shard is READY.
+    pub fn get(&self) -> Option<JsonObject> {
+        self.inner.read().unwrap().clone()
+    }
+
     pub fn set_ready(&self, payload: JsonObject) {
         *self.inner.write().unwrap() = Some(payload);
         self.changed.notify_waiters();
@@ -50,27 +76,517 @@ impl Ready {
     }
 }
 
+struct HistoryInner {
+    events: VecDeque<BroadcastMessage>,
+    next_index: u64,
+}
+
+/// Bounded history of the events broadcast by a shard, used to replay
+/// missed events to resuming clients.
+pub struct History {
+    inner: Mutex<HistoryInner>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(HistoryInner {
+                events: VecDeque::with_capacity(capacity),
+                next_index: 0,
+            }),
+            capacity,
+        }
+    }
+
+    /// Index that the next event pushed to the history will have.
+    pub fn next_index(&self) -> u64 {
+        self.inner.lock().unwrap().next_index
+    }
+
+    /// Assign the next index to an event and remember it, evicting the
+    /// oldest event if the history is full.
+    pub fn push(&self, mut message: BroadcastMessage) -> BroadcastMessage {
+        let mut inner = self.inner.lock().unwrap();
+
+        message.index = inner.next_index;
+        inner.next_index += 1;
+
+        if self.capacity > 0 {
+            if inner.events.len() == self.capacity {
+                inner.events.pop_front();
+            }
+
+            inner.events.push_back(message.clone());
+        }
+
+        message
+    }
+
+    /// Get all events starting at `index` that are still in the history.
+    ///
+    /// Returns [`None`] if some of these events were already evicted.
+    pub fn since(&self, index: u64) -> Option<Vec<BroadcastMessage>> {
+        let inner = self.inner.lock().unwrap();
+
+        if index >= inner.next_index {
+            return Some(Vec::new());
+        }
+
+        let first_index = inner.events.front()?.index;
+
+        if index < first_index {
+            return None;
+        }
+
+        Some(
+            inner
+                .events
+                .range((index - first_index) as usize..)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Get the index of the `count`th last event before `next_index` that
+    /// matches `filter`.
+    ///
+    /// Returns [`None`] if the history does not go back that far.
+    pub fn rewind(
+        &self,
+        next_index: u64,
+        count: usize,
+        filter: impl Fn(&BroadcastMessage) -> bool,
+    ) -> Option<u64> {
+        if count == 0 {
+            return Some(next_index);
+        }
+
+        let inner = self.inner.lock().unwrap();
+        let first_index = inner.events.front()?.index;
+        let end = next_index.checked_sub(first_index)? as usize;
+
+        inner
+            .events
+            .range(..end.min(inner.events.len()))
+            .rev()
+            .filter(|message| filter(message))
+            .nth(count - 1)
+            .map(|message| message.index)
+    }
+}
+
+/// Broadcast channels for the events of a shard.
+///
+/// Changing the backpressure creates a new channel for clients that subscribe
+/// afterwards, while older clients keep receiving events on their channel.
+pub struct Events {
+    /// Senders of all channels that may have receivers, the newest one last.
+    senders: RwLock<Vec<broadcast::Sender<BroadcastMessage>>>,
+}
+
+impl Events {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            senders: RwLock::new(vec![broadcast::channel(capacity).0]),
+        }
+    }
+
+    /// Subscribe to the newest channel.
+    ///
+    /// The receiver is closed right away if the channels were closed.
+    pub fn subscribe(&self) -> broadcast::Receiver<BroadcastMessage> {
+        self.senders
+            .read()
+            .unwrap()
+            .last()
+            .map_or_else(|| broadcast::channel(1).1, broadcast::Sender::subscribe)
+    }
+
+    /// Send an event to all channels.
+    pub fn send(&self, message: &BroadcastMessage) {
+        let senders = self.senders.read().unwrap();
+
+        for sender in senders.iter() {
+            let _res = sender.send(message.clone());
+        }
+
+        let has_unused = senders
+            .iter()
+            .rev()
+            .skip(1)
+            .any(|sender| sender.receiver_count() == 0);
+
+        drop(senders);
+
+        if has_unused {
+            self.resize(None);
+        }
+    }
+
+    /// Remove older channels without receivers and create a new channel for
+    /// new clients if `capacity` is given.
+    pub fn resize(&self, capacity: Option<usize>) {
+        let mut senders = self.senders.write().unwrap();
+
+        let Some(newest) = senders.pop() else {
+            return;
+        };
+
+        senders.retain(|sender| sender.receiver_count() > 0);
+        senders.push(newest);
+
+        if let Some(capacity) = capacity {
+            senders.push(broadcast::channel(capacity).0);
+        }
+    }
+
+    /// Close all channels, which makes all receivers return an error.
+    pub fn close(&self) {
+        self.senders.write().unwrap().clear();
+    }
+}
+
 /// State of a single shard.
 pub struct Shard {
     /// ID of this shard.
     pub id: u32,
     /// Sender for this shard.
     pub sender: MessageSender,
+    /// Rate limited sender for commands from clients.
+    pub commands: Commands,
+    /// Commands from clients whose responses go to the session that sent them.
+    pub responses: Responses,
+    /// Presence that clients set for this shard.
+    pub presence: Presence,
+    /// Voice connections of the bot user on this shard.
+    pub voice: Voice,
     /// Handle for broadcasting events for this shard.
-    pub events: broadcast::Sender<BroadcastMessage>,
+    pub events: Events,
+    /// Recently broadcast events for this shard.
+    pub history: History,
     /// READY state manager for this shard.
     pub ready: Ready,
     /// Cache for guilds on this shard.
     pub guilds: cache::Guilds,
+    /// Worker groups that events of this shard are partitioned between.
+    pub groups: Groups,
+    /// Connection status of this shard, as last reported by its dispatch task.
+    pub status: Mutex<Status>,
+}
+
+impl Shard {
+    /// Send a dispatch that the proxy created to a single session.
+    ///
+    /// It is kept in the history like any other event, so that it is replayed
+    /// if the session resumes.
+    pub fn send_to_session(&self, session_id: &str, payload: String) {
+        let message = self
+            .history
+            .push(BroadcastMessage::targeted(session_id, payload));
+
+        self.events.send(&message);
+    }
+}
+
+/// Connection status and latency of a shard.
+#[derive(Clone, Copy)]
+pub struct Status {
+    pub connection: ConnectionState,
+    pub latency: Option<Duration>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            connection: ConnectionState::Disconnected {
+                reconnect_attempts: 0,
+            },
+            latency: None,
+        }
+    }
+}
+
+/// Maps the sequence numbers of a session to indices in the shard's [`History`].
+#[derive(Clone, Copy, Default)]
+struct Position {
+    /// Last sequence number sent to the client.
+    seq: usize,
+    /// History index of the next event to send to the client.
+    next_index: u64,
+    /// Lowest sequence number from which events were sent without gaps.
+    first_seq: usize,
+}
+
+/// Address that a client connected from.
+#[derive(Clone, Copy)]
+pub enum Address {
+    Tcp(SocketAddr),
+    /// Unix domain socket, with the process ID of the peer if it is known.
+    Unix(Option<i32>),
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix(Some(pid)) => write!(f, "unix:{pid}"),
+            Self::Unix(None) => f.write_str("unix"),
+        }
+    }
+}
+
+/// A client connection to a session.
+#[derive(Clone, Copy)]
+pub struct Client {
+    /// Unique ID of the client connection.
+    pub id: u64,
+    /// Address of the client.
+    pub addr: Address,
+    /// Transport compression requested by the client.
+    pub compression: TransportCompression,
+    /// Time at which the client connected.
+    pub connected_at: SystemTime,
+}
+
+impl Client {
+    pub fn new(addr: Address, compression: TransportCompression) -> Self {
+        Self {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            addr,
+            compression,
+            connected_at: SystemTime::now(),
+        }
+    }
+}
+
+/// Which client is connected to a session and when it was last active.
+#[derive(Clone, Copy)]
+struct Activity {
+    client: Option<Client>,
+    last_seen: Instant,
 }
 
 /// A session initiated by a client.
-#[derive(Clone)]
 pub struct Session {
+    /// ID of this session, as sent in READY.
+    pub id: String,
     /// Shard ID that this session is for.
     pub shard_id: u32,
     /// Compression as requested in IDENTIFY.
     pub compress: Option<bool>,
+    /// Intents as requested in IDENTIFY.
+    pub intents: Intents,
+    /// Worker group that this session is a member of.
+    pub group: Option<String>,
+    /// Name of the credential that the client identified with.
+    pub credential: Option<String>,
+    /// Position of this session in the shard's event history.
+    position: Mutex<Position>,
+    /// Connection state of this session.
+    activity: Mutex<Activity>,
+    /// Notified when the client has to be disconnected.
+    kicked: Notify,
+    /// Events that clients of this session missed because they lagged behind.
+    lagged_events: AtomicU64,
+    /// Commands that clients of this session sent to Discord.
+    commands: AtomicU64,
+    /// Depth of the queue of the client that last received events.
+    queue: Mutex<Option<queue::Depth>>,
+}
+
+impl Session {
+    /// Create a new session that a client is connected to.
+    pub fn new(
+        shard_id: u32,
+        compress: Option<bool>,
+        intents: Intents,
+        group: Option<String>,
+        credential: Option<String>,
+        client: Client,
+    ) -> Self {
+        // Session IDs are 32 bytes of ASCII
+        let mut rng = thread_rng();
+        let id: String = std::iter::repeat(())
+            .map(|()| rng.sample(Alphanumeric))
+            .map(char::from)
+            .take(32)
+            .collect();
+
+        Self {
+            id,
+            shard_id,
+            compress,
+            intents,
+            group,
+            credential,
+            position: Mutex::new(Position::default()),
+            activity: Mutex::new(Activity {
+                client: Some(client),
+                last_seen: Instant::now(),
+            }),
+            kicked: Notify::new(),
+            lagged_events: AtomicU64::new(0),
+            commands: AtomicU64::new(0),
+            queue: Mutex::new(None),
+        }
+    }
+
+    /// Count a command that a client sent to Discord.
+    pub fn add_command(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Commands that clients of this session sent to Discord.
+    pub fn commands(&self) -> u64 {
+        self.commands.load(Ordering::Relaxed)
+    }
+
+    /// Report the depth of the queue of the client that receives events.
+    pub fn track_queue(&self, depth: queue::Depth) {
+        *self.queue.lock().unwrap() = Some(depth);
+    }
+
+    /// Messages and bytes queued for the connected client, if any.
+    pub fn queue_depth(&self) -> Option<(usize, usize)> {
+        self.queue
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(queue::Depth::get)
+    }
+
+    /// Count events that the client missed because it lagged behind.
+    pub fn add_lagged_events(&self, amount: u64) {
+        self.lagged_events.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Events that clients of this session missed because they lagged behind.
+    pub fn lagged_events(&self) -> u64 {
+        self.lagged_events.load(Ordering::Relaxed)
+    }
+
+    /// Mark this session as connected to a client.
+    pub fn attach(&self, client: Client) {
+        *self.activity.lock().unwrap() = Activity {
+            client: Some(client),
+            last_seen: Instant::now(),
+        };
+    }
+
+    /// Mark this session as no longer connected to a client.
+    ///
+    /// Returns false and does nothing if the client is no longer the one
+    /// connected to the session, because another client took it over.
+    pub fn detach(&self, client_id: u64) -> bool {
+        let mut activity = self.activity.lock().unwrap();
+
+        if activity.client.is_none_or(|client| client.id != client_id) {
+            return false;
+        }
+
+        *activity = Activity {
+            client: None,
+            last_seen: Instant::now(),
+        };
+
+        drop(activity);
+
+        true
+    }
+
+    /// Disconnect the client that is currently connected to this session.
+    pub fn kick(&self) {
+        *self.activity.lock().unwrap() = Activity {
+            client: None,
+            last_seen: Instant::now(),
+        };
+
+        self.kicked.notify_waiters();
+    }
+
+    /// Get the client that is currently connected to this session.
+    pub fn client(&self) -> Option<Client> {
+        self.activity.lock().unwrap().client
+    }
+
+    /// Wait until a client has to be disconnected because it was kicked or
+    /// another client resumed the session.
+    pub async fn wait_kicked(&self, client_id: u64) {
+        loop {
+            let notified = self.kicked.notified();
+            pin!(notified);
+            notified.as_mut().enable();
+
+            if self.client().is_none_or(|client| client.id != client_id) {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+
+    /// Record activity of the connected client.
+    pub fn touch(&self) {
+        self.activity.lock().unwrap().last_seen = Instant::now();
+    }
+
+    /// Whether a client is currently connected to this session.
+    pub fn is_connected(&self) -> bool {
+        self.activity.lock().unwrap().client.is_some()
+    }
+
+    /// Whether this session was detached for longer than the resume window.
+    pub fn is_expired(&self, resume_window: Duration) -> bool {
+        let activity = *self.activity.lock().unwrap();
+
+        activity.client.is_none() && activity.last_seen.elapsed() > resume_window
+    }
+
+    /// Record that the event before `next_index` was sent to the client with
+    /// sequence number `seq`.
+    ///
+    /// If `contiguous` is false, events were skipped and older sequence numbers
+    /// can no longer be resumed from.
+    pub fn set_position(&self, seq: usize, next_index: u64, contiguous: bool) {
+        let mut position = self.position.lock().unwrap();
+
+        position.seq = seq;
+        position.next_index = next_index;
+
+        if !contiguous {
+            position.first_seq = seq;
+        }
+    }
+
+    /// Whether the client should receive an event based on its intents and
+    /// the partitioning of its worker group.
+    ///
+    /// Responses to commands only go to the session that sent the command.
+    pub fn wants(&self, message: &BroadcastMessage) -> bool {
+        if let Some(target) = &message.target {
+            return *target == self.id;
+        }
+
+        (message.intents.is_empty() || self.intents.intersects(message.intents))
+            && (self.group.is_none() || message.owners.contains(&self.id))
+    }
+
+    /// Get the history index of the first event a client resuming with
+    /// sequence number `seq` has not seen yet.
+    pub fn resume_index(&self, seq: usize, history: &History) -> Option<u64> {
+        let position = *self.position.lock().unwrap();
+
+        if seq < position.first_seq || seq > position.seq {
+            return None;
+        }
+
+        // Events filtered by intents did not get a sequence number, so count
+        // back over the events that were actually sent
+        history.rewind(position.next_index, position.seq - seq, |message| {
+            self.wants(message)
+        })
+    }
 }
 
 /// Global state for all shards managed by the proxy.
@@ -80,33 +596,160 @@ pub struct Inner {
     /// Total shard count.
     pub shard_count: u32,
     /// All sessions active in the proxy.
-    pub sessions: RwLock<HashMap<String, Session>>,
+    pub sessions: RwLock<HashMap<String, Arc<Session>>>,
+    /// Tasks relaying the events of the shards.
+    pub dispatch_tasks: Mutex<JoinSet<Option<SavedShard>>>,
+    /// Whether these shards were replaced by a new set of shards.
+    retired: AtomicBool,
 }
 
 impl Inner {
-    /// Get a session by its ID.
-    pub fn get_session(&self, session_id: &str) -> Option<Session> {
-        self.sessions.read().unwrap().get(session_id).cloned()
+    pub fn new(shards: Vec<Arc<Shard>>, shard_count: u32) -> Self {
+        Self {
+            shards,
+            shard_count,
+            sessions: RwLock::new(HashMap::new()),
+            dispatch_tasks: Mutex::new(JoinSet::new()),
+            retired: AtomicBool::new(false),
+        }
+    }
+
+    /// Get a shard by its ID, if it is managed by the proxy.
+    pub fn shard(&self, shard_id: u32) -> Option<Arc<Shard>> {
+        let first_id = self.shards.first()?.id;
+
+        self.shards
+            .get(shard_id.checked_sub(first_id)? as usize)
+            .cloned()
+    }
+
+    /// Whether these shards were replaced by a new set of shards.
+    pub fn is_retired(&self) -> bool {
+        self.retired.load(Ordering::Relaxed)
+    }
+
+    /// Shut down these shards after they were replaced by a new set of shards.
+    ///
+    /// All sessions are removed and connected clients are told to reconnect.
+    pub fn retire(&self) {
+        self.retired.store(true, Ordering::Relaxed);
+        self.sessions.write().unwrap().clear();
+
+        for shard in &self.shards {
+            shard.events.close();
+            let _res = shard.sender.close(CloseFrame::NORMAL);
+        }
+
+        // The tasks return once their shard is closed
+        self.dispatch_tasks.lock().unwrap().detach_all();
+    }
+
+    /// Get a session by its ID, unless it has expired.
+    pub fn get_session(&self, session_id: &str) -> Option<Arc<Session>> {
+        self.sessions
+            .read()
+            .unwrap()
+            .get(session_id)
+            .filter(|session| !session.is_expired(config::current().resume_window()))
+            .cloned()
     }
 
     /// Create a new session.
-    pub fn create_session(&self, session: Session) -> String {
-        // Session IDs are 32 bytes of ASCII
-        let mut rng = thread_rng();
-        let session_id: String = std::iter::repeat(())
-            .map(|()| rng.sample(Alphanumeric))
-            .map(char::from)
-            .take(32)
-            .collect();
+    pub fn create_session(&self, session: Session) -> Arc<Session> {
+        let session = Arc::new(session);
+
+        self.sessions
+            .write()
+            .unwrap()
+            .insert(session.id.clone(), session.clone());
+
+        session
+    }
+
+    /// Remove a session and disconnect its client.
+    ///
+    /// Returns false if there is no such session.
+    pub fn kick_session(&self, session_id: &str) -> bool {
+        let Some(session) = self.sessions.write().unwrap().remove(session_id) else {
+            return false;
+        };
+
+        session.kick();
+
+        if let Some(shard) = self.shard(session.shard_id) {
+            shard.groups.leave(session.shard_id, &session);
+        }
+
+        true
+    }
 
+    /// Remove all sessions of a shard, so that clients can no longer resume them.
+    ///
+    /// Clients that are still connected keep receiving events, but have to
+    /// identify again after disconnecting.
+    pub fn invalidate_sessions(&self, shard_id: u32) {
         self.sessions
             .write()
             .unwrap()
-            .insert(session_id.clone(), session);
+            .retain(|_, session| session.shard_id != shard_id);
+    }
+
+    /// Remove all sessions that were detached for longer than the resume window
+    /// and update the session metrics.
+    pub fn expire_sessions(&self) {
+        let resume_window = config::current().resume_window();
+        let mut sessions = self.sessions.write().unwrap();
+
+        let before = sessions.len();
+        sessions.retain(|_, session| !session.is_expired(resume_window));
+        let expired = before - sessions.len();
+
+        let active = sessions
+            .values()
+            .filter(|session| session.is_connected())
+            .count();
+        let detached = sessions.len() - active;
+
+        drop(sessions);
+
+        if expired > 0 {
+            debug!("Expired {expired} sessions");
+        }
+
+        metrics::gauge!("gateway_sessions_active").set(active as f64);
+        metrics::gauge!("gateway_sessions_detached").set(detached as f64);
+        metrics::counter!("gateway_sessions_expired").increment(expired as u64);
+    }
+}
+
+/// Periodically remove expired sessions.
+pub async fn collect_sessions(state: State) {
+    let mut interval = interval(SESSION_COLLECT_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        state.get().expire_sessions();
+    }
+}
+
+/// Holder of the current [`Inner`] state, which is replaced after resharding.
+pub struct Current(RwLock<Arc<Inner>>);
+
+impl Current {
+    pub const fn new(inner: Arc<Inner>) -> Self {
+        Self(RwLock::new(inner))
+    }
+
+    /// Get the current state.
+    pub fn get(&self) -> Arc<Inner> {
+        self.0.read().unwrap().clone()
+    }
 
-        session_id
+    /// Replace the current state, returning the previous one.
+    pub fn replace(&self, inner: Arc<Inner>) -> Arc<Inner> {
+        std::mem::replace(&mut *self.0.write().unwrap(), inner)
     }
 }
 
-/// A reference to the [`StateInner`] of the proxy.
-pub type State = Arc<Inner>;
+/// A reference to the [`Current`] state of the proxy.
+pub type State = Arc<Current>;
@@ -0,0 +1,94 @@
+use futures_util::StreamExt;
+use inotify::WatchMask;
+use tokio_rustls::{
+    rustls::{
+        crypto::aws_lc_rs,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tracing::{error, info};
+
+use std::{
+    error::Error,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use crate::config::{self, Tls};
+
+/// TLS acceptor for the client listener whose certificate can be swapped out
+/// while the proxy is running.
+pub struct Acceptor {
+    inner: RwLock<TlsAcceptor>,
+    tls: Tls,
+}
+
+impl Acceptor {
+    pub fn new(tls: Tls) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let inner = RwLock::new(load(&tls)?);
+
+        Ok(Self { inner, tls })
+    }
+
+    /// The acceptor with the most recently loaded certificate.
+    pub fn get(&self) -> TlsAcceptor {
+        self.inner.read().unwrap().clone()
+    }
+
+    /// Load the certificate and key again. Connections that are already
+    /// established keep using the old certificate.
+    pub fn reload(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let acceptor = load(&self.tls)?;
+        *self.inner.write().unwrap() = acceptor;
+
+        Ok(())
+    }
+}
+
+fn load(tls: &Tls) -> Result<TlsAcceptor, Box<dyn Error + Send + Sync>> {
+    let certs = CertificateDer::pem_file_iter(&tls.cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key)?;
+
+    let mut server_config =
+        ServerConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+
+    // Websocket upgrades require HTTP/1.1
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Reload the certificate whenever it or its key changes on disk.
+pub async fn watch_certificate(acceptor: Arc<Acceptor>) {
+    // The directories are watched instead of the files because tools like
+    // certbot replace the files, or the symlinks to them, on renewal
+    let directories: Vec<_> = [&acceptor.tls.cert, &acceptor.tls.key]
+        .into_iter()
+        .map(|path| match Path::new(path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        })
+        .collect();
+
+    let mask = WatchMask::MODIFY | WatchMask::CLOSE_WRITE | WatchMask::CREATE | WatchMask::MOVED_TO;
+
+    let mut events = match config::watch_paths(&directories, mask) {
+        Ok(events) => events,
+        Err(e) => {
+            error!("Failed to watch TLS certificate, it cannot be reloaded on the fly: {e}");
+            return;
+        }
+    };
+
+    while let Some(Ok(_)) = events.next().await {
+        match acceptor.reload() {
+            Ok(()) => info!("TLS certificate was modified, reloaded"),
+            Err(e) => error!("Failed to reload TLS certificate: {e}"),
+        }
+    }
+}
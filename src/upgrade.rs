@@ -10,33 +10,59 @@ use hyper::{
     upgrade, Request, Response,
 };
 use hyper_util::rt::TokioIo;
+use percent_encoding::percent_decode_str;
 use ring::digest;
 use tracing::error;
 
-use std::net::SocketAddr;
+use std::borrow::Cow;
 
-use crate::{server::handle_client, state::State};
+use crate::{
+    compression::TransportCompression,
+    encoding::Encoding,
+    server::handle_client,
+    state::{Address, State},
+};
 
 /// Websocket GUID constant as specified in RFC6455:
 /// <https://datatracker.ietf.org/doc/html/rfc6455#section-1.3>
 const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
+/// Get the percent-decoded value of a query string parameter.
+///
+/// Values that are not valid UTF-8 after decoding are treated as missing.
+pub fn query_param<'a>(query: &'a str, key: &str) -> Option<Cow<'a, str>> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)
+        .and_then(|(_, value)| percent_decode_str(value).decode_utf8().ok())
+}
+
 /// Accept a websocket upgrade request and start processing the client's
 /// events afterwards.
 ///
 /// This method is one of two parts in the communication between server
-/// and client where zlib-stream compression may be requested.
+/// and client where zlib-stream or zstd-stream compression may be requested.
 pub fn server(
-    addr: SocketAddr,
+    addr: Address,
     mut request: Request<Incoming>,
     state: State,
 ) -> Response<Full<Bytes>> {
     let uri = request.uri();
     let query = uri.query();
 
-    // Track whether the client requested zlib encoding in the query
+    // Track whether the client requested transport compression in the query
     // string parameters
-    let use_zlib = query.is_some_and(|q| q.contains("compress=zlib-stream"));
+    let compression = query.map_or(TransportCompression::None, TransportCompression::from_query);
+
+    // Track whether the client requested ETF instead of JSON
+    let encoding = query.map_or(Encoding::Json, Encoding::from_query);
+
+    // Track whether the client wants to join a worker group
+    let group = query
+        .and_then(|query| query_param(query, "group"))
+        .filter(|group| !group.is_empty())
+        .map(Cow::into_owned);
 
     let mut response = Response::new(Full::default());
 
@@ -56,7 +82,15 @@ pub fn server(
         tokio::spawn(async move {
             match upgrade::on(&mut request).await {
                 Ok(upgraded) => {
-                    let _res = handle_client(addr, TokioIo::new(upgraded), state, use_zlib).await;
+                    let _res = handle_client(
+                        addr,
+                        TokioIo::new(upgraded),
+                        state,
+                        encoding,
+                        compression,
+                        group,
+                    )
+                    .await;
                 }
                 Err(e) => error!("[{}] Websocket upgrade error: {}", addr, e),
             }
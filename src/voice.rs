@@ -0,0 +1,165 @@
+use twilight_model::gateway::payload::outgoing::UpdateVoiceState;
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{
+    dispatch::BroadcastMessage,
+    groups,
+    state::{Inner, Session},
+};
+
+/// Voice connection of the bot user in a guild.
+struct Connection {
+    /// ID of the session that joined the voice channel.
+    session_id: String,
+    /// Name of the credential of that session.
+    credential: Option<String>,
+    /// Last `VOICE_STATE_UPDATE` of the bot user in the guild.
+    state: Option<String>,
+    /// Last `VOICE_SERVER_UPDATE` for the guild.
+    server: Option<String>,
+}
+
+impl Connection {
+    fn new(session: &Session) -> Self {
+        Self {
+            session_id: session.id.clone(),
+            credential: session.credential.clone(),
+            state: None,
+            server: None,
+        }
+    }
+}
+
+/// Voice connections of the bot user on a shard, each tied to the session
+/// that sent the voice state update (op 4) for its guild.
+pub struct Voice {
+    /// ID of the bot user, known once the shard is READY.
+    user_id: AtomicU64,
+    /// Voice connections by guild ID.
+    connections: Mutex<HashMap<u64, Connection>>,
+}
+
+impl Voice {
+    pub fn new() -> Self {
+        Self {
+            user_id: AtomicU64::new(0),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_user_id(&self, user_id: u64) {
+        self.user_id.store(user_id, Ordering::Relaxed);
+    }
+
+    /// Forget all voice connections, which do not survive a new session of
+    /// the shard.
+    pub fn clear(&self) {
+        self.connections.lock().unwrap().clear();
+    }
+
+    /// Tie the voice connection in a guild to the session that is about to
+    /// send a voice state update for it.
+    pub fn track(&self, session: &Session, payload: &str) {
+        #[cfg(feature = "simd-json")]
+        let maybe_update =
+            unsafe { simd_json::from_str::<UpdateVoiceState>(&mut payload.to_owned()) };
+        #[cfg(not(feature = "simd-json"))]
+        let maybe_update = serde_json::from_str::<UpdateVoiceState>(payload);
+
+        let Ok(update) = maybe_update else {
+            return;
+        };
+
+        let mut connections = self.connections.lock().unwrap();
+        let connection = connections
+            .entry(update.d.guild_id.get())
+            .or_insert_with(|| Connection::new(session));
+
+        if connection.session_id != session.id {
+            *connection = Connection::new(session);
+        }
+
+        drop(connections);
+    }
+
+    /// Get the session that a voice event of the bot user belongs to, and
+    /// remember the event for later sessions of the same client.
+    pub fn route(&self, event_name: &str, payload: &str, guild_id: Option<u64>) -> Option<String> {
+        let is_server = match event_name {
+            "VOICE_SERVER_UPDATE" => true,
+            "VOICE_STATE_UPDATE" => false,
+            _ => return None,
+        };
+
+        let guild_id = guild_id?;
+
+        if !is_server
+            && groups::find_integer(payload, "user_id")
+                != Some(self.user_id.load(Ordering::Relaxed))
+        {
+            return None;
+        }
+
+        let mut connections = self.connections.lock().unwrap();
+        let connection = connections.get_mut(&guild_id)?;
+        let session_id = connection.session_id.clone();
+
+        if is_server {
+            connection.server = Some(payload.to_owned());
+        } else if groups::find_integer(payload, "channel_id").is_none() {
+            // The bot user left the voice channel
+            connections.remove(&guild_id);
+        } else {
+            connection.state = Some(payload.to_owned());
+        }
+
+        drop(connections);
+
+        Some(session_id)
+    }
+
+    /// Hand the voice connections of sessions without a client over to a new
+    /// session with the same credential.
+    ///
+    /// Returns the remembered voice events for the new session, so that its
+    /// client can pick up the voice connections.
+    pub fn adopt(&self, inner: &Inner, session: &Session) -> Vec<BroadcastMessage> {
+        let mut connections = self.connections.lock().unwrap();
+        let mut messages = Vec::new();
+
+        for connection in connections.values_mut() {
+            if connection.credential != session.credential {
+                continue;
+            }
+
+            let is_owned = connection.session_id == session.id
+                || inner
+                    .get_session(&connection.session_id)
+                    .is_some_and(|owner| owner.is_connected());
+
+            if is_owned {
+                continue;
+            }
+
+            connection.session_id.clone_from(&session.id);
+
+            messages.extend(
+                [&connection.state, &connection.server]
+                    .into_iter()
+                    .flatten()
+                    .map(|payload| BroadcastMessage::targeted(&session.id, payload.clone())),
+            );
+        }
+
+        drop(connections);
+
+        messages
+    }
+}